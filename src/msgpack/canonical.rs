@@ -0,0 +1,58 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use decoder::{Decoder, DecoderLimits};
+use encoder::{Encoder, NegativeZero};
+use error::*;
+
+use std::default::Default;
+use std::io;
+use std::io::Decorator;
+use std::io::mem;
+
+/// Checks whether `data` holds exactly one value, written the way canonical
+/// mode on `Encoder` would write it with the given negative zero policy.
+/// This is for checking data from elsewhere before it is hashed or signed.
+/// Data which does not decode at all gives the decoding error. As that data
+/// is usually untrusted, it is read within the conservative limits of
+/// `DecoderLimits::default()`, and data beyond them gives a limit error.
+pub fn is_canonical(data: &[u8], negative_zero: NegativeZero) -> Result<bool, Error> {
+	let mut reader = mem::BufReader::new(data);
+	let (value, consumed) = {
+		let limits : DecoderLimits = Default::default();
+		let mut decoder = Decoder::with_limits(&mut reader as &mut io::Reader, limits);
+		let value = try!(decoder.try_read());
+		(value, decoder.position())
+	};
+	if consumed != data.len() as u64 {
+		return Ok(false);
+	}
+	let mut writer = mem::MemWriter::new();
+	/* encoding cycle */ {
+		let mut encoder = Encoder::new(&mut writer as &mut io::Writer);
+		encoder.set_canonical(true);
+		encoder.set_negative_zero(negative_zero);
+		try!(encoder.write(&value));
+	}
+	Ok(writer.inner().as_slice() == data)
+}
@@ -21,16 +21,31 @@
 	distribution.
  */
 
+use error::*;
+use extension::{ExtensionObject, Registry, RejectUnknown};
+use header;
+use lossless;
 use magic::*;
+use profile::JsonProfile;
+use timestamp;
+use timestamp::Timestamp;
+use utf8;
 
-use std::cast;
+use std::cell::Cell;
+use std::cmp;
+use std::default::Default;
+use std::hash::Hash;
+use std::hashmap::{HashMap, HashSet};
 use std::io;
+use std::rc::Rc;
 use std::str;
 use std::vec;
+use std::{u8, u16, u32, u64, uint, i8, i16, i32, i64, int};
 
-static ErrNoData : &'static str = "No valid MsgPack type to read.";
-static ErrInsufficientData : &'static str = "Not enough bytes available to read value.";
+use extra::serialize;
+use extra::treemap::{TreeMap, TreeSet};
 
+#[deriving(Clone)]
 pub enum Value {
 	Signed(i64),
 	Unsigned(u64),
@@ -39,19 +54,444 @@ pub enum Value {
 	Array(~[Value]),
 	Float32(f32),
 	Float64(f64),
+	/// An extension MsgPack does not define. `Decoder::try_read` and the
+	/// readers built on it never give type -1 as one: it is decoded as
+	/// `Time`, or refused with `InvalidTimestamp` if its payload is not a
+	/// valid timestamp. `Decoder::read_lossless` keeps every extension as
+	/// it was written, type -1 included, and so does
+	/// `lossless::Value::to_value`.
 	Extension(i8, ~[u8]),
-	// TODO: Map.
+	/// A timestamp extension (type -1).
+	Time(Timestamp),
+	/// An extension which a registry handler turned in to an application
+	/// value.
+	Custom(ExtensionObject),
+	/* pairs are kept in wire order, duplicate keys included */
+	Map(~[(Value, Value)]),
 	Boolean(bool),
 	Nil
 }
 
+/* Integers compare by their numeric value, as the encoder writes any
+ * non-negative integer in the unsigned family. */
+impl Eq for Value {
+	fn eq(&self, other: &Value) -> bool {
+		match (self, other) {
+			(&Signed(a), &Signed(b)) => a == b,
+			(&Unsigned(a), &Unsigned(b)) => a == b,
+			(&Signed(a), &Unsigned(b)) | (&Unsigned(b), &Signed(a)) =>
+				a >= 0 && a as u64 == b,
+			(&String(ref a), &String(ref b)) => a == b,
+			(&Binary(ref a), &Binary(ref b)) => a == b,
+			(&Array(ref a), &Array(ref b)) => a == b,
+			(&Float32(a), &Float32(b)) => a == b,
+			(&Float64(a), &Float64(b)) => a == b,
+			(&Extension(t, ref a), &Extension(u, ref b)) => t == u && a == b,
+			(&Time(a), &Time(b)) => a == b,
+			(&Custom(ref a), &Custom(ref b)) => a == b,
+			(&Map(ref a), &Map(ref b)) => a == b,
+			(&Boolean(a), &Boolean(b)) => a == b,
+			(&Nil, &Nil) => true,
+			_ => false
+		}
+	}
+}
+
+/* Most elements reserved up front for a container, whatever its header
+ * claims; larger containers grow as their elements actually arrive. */
+static ReserveLimit : uint = 4096;
+
+/// Bounds on what a `Decoder` will accept, for use with untrusted input.
+/// Exceeding a size limit is reported as `SizeLimitExceeded`, and nesting
+/// too deeply as `DepthLimitExceeded`.
+pub struct DecoderLimits {
+	/// Deepest nesting of arrays and maps; a scalar at the top level is at
+	/// depth zero, the elements of a top level array at depth one.
+	max_depth: uint,
+	/// Most elements an array, or pairs a map, may hold.
+	max_container_len: uint,
+	/// Most bytes in a single string.
+	max_str_len: uint,
+	/// Most bytes in a single binary.
+	max_bin_len: uint,
+	/// Most bytes in a single extension payload.
+	max_ext_len: uint,
+	/// Most bytes which may be read over the life of the decoder.
+	max_total_bytes: u64
+}
+
+impl DecoderLimits {
+	/// Limits which never trigger; only suitable for trusted input.
+	pub fn unlimited() -> DecoderLimits {
+		DecoderLimits {
+			max_depth: uint::max_value,
+			max_container_len: uint::max_value,
+			max_str_len: uint::max_value,
+			max_bin_len: uint::max_value,
+			max_ext_len: uint::max_value,
+			max_total_bytes: u64::max_value
+		}
+	}
+}
+
+/// Conservative limits for messages from untrusted peers: 64 levels of
+/// nesting, 65536 elements per container, 1MiB per string, binary or
+/// extension and 16MiB in total.
+impl Default for DecoderLimits {
+	fn default() -> DecoderLimits {
+		DecoderLimits {
+			max_depth: 64,
+			max_container_len: 65536,
+			max_str_len: 1 << 20,
+			max_bin_len: 1 << 20,
+			max_ext_len: 1 << 20,
+			max_total_bytes: 1 << 24
+		}
+	}
+}
+
+/// What a `Decoder` does with a string which is not valid UTF-8.
+#[deriving(Clone, Eq)]
+pub enum Utf8Policy {
+	/// Fail with `InvalidUtf8`.
+	Utf8Strict,
+	/// Replace each invalid sequence with U+FFFD REPLACEMENT CHARACTER.
+	Utf8Lossy,
+	/// Return the data unchanged as `Binary`.
+	Utf8AsBinary
+}
+
+/// The family of a value, as reported by `Decoder::peek_type`.
+#[deriving(Eq)]
+pub enum ValueType {
+	NilType,
+	BooleanType,
+	/// Any member of the int family, signed or not.
+	IntegerType,
+	FloatType,
+	StringType,
+	BinaryType,
+	ArrayType,
+	MapType,
+	/// An extension, along with its type code.
+	ExtensionType(i8)
+}
+
 pub struct Decoder<'a> {
-	priv reader: &'a mut io::Reader
+	priv reader: &'a mut io::Reader,
+	priv limits: DecoderLimits,
+	/* containers currently open */
+	priv depth: uint,
+	/* bytes consumed so far, for error reporting */
+	priv offset: u64,
+	/* tag of the value currently being read, and the offset it was read
+	 * at, for error reporting */
+	priv tag: Option<u8>,
+	priv start: u64,
+	/* bytes which have been looked at but not yet consumed */
+	priv lookahead: ~[u8],
+	priv utf8_policy: Utf8Policy,
+	/* set once a string of the current top-level value fails UTF-8
+	 * validation */
+	priv invalid_utf8: bool,
+	/* calls of the public readers under way, so that only the outermost
+	 * starts a new top-level value */
+	priv nesting: uint,
+	priv profile: Option<JsonProfile>,
+	/* set whenever an error is built, so that the profile starts afresh
+	 * with the next value */
+	priv failed: Cell<bool>,
+	priv registry: Option<Rc<Registry>>
 }
 
 impl<'a> Decoder<'a> {
+	/// Creates a decoder without any limits; see `with_limits` for reading
+	/// untrusted input.
 	pub fn new(reader: &'a mut io::Reader) -> Decoder {
-		Decoder { reader: reader }
+		Decoder::with_limits(reader, DecoderLimits::unlimited())
+	}
+
+	/// Creates a decoder which refuses input exceeding `limits`.
+	pub fn with_limits(reader: &'a mut io::Reader, limits: DecoderLimits) -> Decoder {
+		Decoder {
+			reader: reader,
+			limits: limits,
+			depth: 0,
+			offset: 0,
+			tag: None,
+			start: 0,
+			lookahead: ~[],
+			utf8_policy: Utf8Strict,
+			invalid_utf8: false,
+			nesting: 0,
+			profile: None,
+			failed: Cell::new(false),
+			registry: None
+		}
+	}
+
+	/// Sets what happens to strings which are not valid UTF-8; the default
+	/// is `Utf8Strict`.
+	pub fn set_utf8_policy(&mut self, policy: Utf8Policy) {
+		self.utf8_policy = policy;
+	}
+
+	/// Returns true if a string in the last top-level value was not valid
+	/// UTF-8, whatever the policy made of it. A top-level value is whatever
+	/// the outermost call of `try_read`, `read`, `read_as` or
+	/// `read_lossless` reads, and the flag is cleared when the next one
+	/// starts. It does not say which string it was; under `Utf8AsBinary`,
+	/// `read_lossless` keeps the tag of each string, so that a string let
+	/// through as a binary can be told from a real binary.
+	pub fn saw_invalid_utf8(&self) -> bool {
+		self.invalid_utf8
+	}
+
+	/// Turns compatibility mode on or off; it is off by default. Peers
+	/// using the MsgPack spec from before 2013 write binary data as the old
+	/// raw type, which arrives here as a string. Compatibility mode is the
+	/// same as the `Utf8AsBinary` policy. Turning it off restores
+	/// `Utf8Strict` if that policy is in use, and otherwise leaves the
+	/// policy as it is.
+	pub fn set_compatibility(&mut self, on: bool) {
+		if on {
+			self.utf8_policy = Utf8AsBinary;
+		} else if self.utf8_policy == Utf8AsBinary {
+			self.utf8_policy = Utf8Strict;
+		}
+	}
+
+	/// Turns the JSON profile on or off; it is off by default. Under the
+	/// profile, only values which convert to JSON without loss are read:
+	/// binaries, extensions, and map keys which are not strings all fail
+	/// with `ProfileViolation`. Turn the profile on or off only between
+	/// top-level values.
+	pub fn set_json_profile(&mut self, on: bool) {
+		self.profile = if on { Some(JsonProfile::new()) } else { None };
+	}
+
+	/// Attaches an extension registry, or removes it given `None`. Types
+	/// with a handler are then read as `Custom` values, and what happens to
+	/// the rest is up to the registry's policy. Without a registry, every
+	/// extension besides the timestamp is read as a plain `Extension`.
+	pub fn set_registry(&mut self, registry: Option<Rc<Registry>>) {
+		self.registry = registry;
+	}
+
+	/// Returns the number of bytes consumed from the reader so far. Bytes
+	/// which were only peeked at are not counted.
+	pub fn position(&self) -> u64 {
+		self.offset
+	}
+}
+
+/* Byte-level reading; every read from the underlying stream goes through
+ * `pull`, and every consumed byte through `fill`, so the offset stays
+ * accurate. */
+impl<'a> Decoder<'a> {
+	/// Builds an error of the given kind at the start of the value being
+	/// read. This is for `Decodable` implementations which find a value
+	/// they cannot use.
+	pub fn error(&self, kind: ErrorKind) -> Error {
+		self.failed.set(true);
+		Error::new(kind, self.start, self.tag)
+	}
+
+	/* Reads exactly `buf.len()` bytes straight from the reader, without
+	 * touching the lookahead or the offset. */
+	fn pull(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+		let mut done = 0;
+		while done < buf.len() {
+			let result = {
+				let reader = &mut self.reader;
+				let dst = buf.mut_slice_from(done);
+				trap_io(|| reader.read(dst))
+			};
+			let at = self.offset + (self.lookahead.len() + done) as u64;
+			if !result.is_ok() {
+				self.failed.set(true);
+			}
+			match result {
+				Ok(Some(n)) => done += n,
+				Ok(None) => return Err(Error::new(UnexpectedEof, at, self.tag)),
+				Err(e) => match e.kind {
+					io::EndOfFile => return Err(Error::new(UnexpectedEof, at, self.tag)),
+					_ => return Err(Error::new(IoFailure(e), at, self.tag))
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/* Makes sure at least `len` bytes are waiting in the lookahead. Bytes
+	 * are moved in one at a time, so none are lost if the reader fails. */
+	fn peek(&mut self, len: uint) -> Result<(), Error> {
+		if (len as u64) > self.limits.max_total_bytes - self.offset {
+			return Err(self.error(SizeLimitExceeded));
+		}
+		while self.lookahead.len() < len {
+			let mut buf = [0u8, ..1];
+			try!(self.pull(buf));
+			self.lookahead.push(buf[0]);
+		}
+		Ok(())
+	}
+
+	/* Reads a big-endian number of `width` bytes, `at` bytes in to the
+	 * lookahead, without consuming anything. */
+	fn peek_be(&mut self, at: uint, width: uint) -> Result<uint, Error> {
+		try!(self.peek(at + width));
+		let mut x : uint = 0;
+		for b in self.lookahead.slice(at, at + width).iter() {
+			x = (x << 8) | *b as uint;
+		}
+		Ok(x)
+	}
+
+	fn fill(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+		if (buf.len() as u64) > self.limits.max_total_bytes - self.offset {
+			return Err(self.error(SizeLimitExceeded));
+		}
+		let queued = cmp::min(buf.len(), self.lookahead.len());
+		if queued > 0 {
+			for i in range(0, queued) {
+				buf[i] = self.lookahead[i];
+			}
+			self.lookahead = self.lookahead.slice_from(queued).to_owned();
+			self.offset += queued as u64;
+		}
+		let rest = buf.mut_slice_from(queued);
+		try!(self.pull(rest));
+		self.offset += rest.len() as u64;
+		Ok(())
+	}
+
+	/* Reads in chunks, so a header which lies about its length costs no
+	 * more memory than the data which actually arrives. */
+	fn read_chunked(&mut self, len: uint) -> Result<~[u8], Error> {
+		let mut data : ~[u8] = vec::with_capacity(cmp::min(len, ReserveLimit));
+		let mut chunk = [0u8, ..ReserveLimit];
+		while data.len() < len {
+			let n = cmp::min(len - data.len(), chunk.len());
+			try!(self.fill(chunk.mut_slice_to(n)));
+			data.push_all(chunk.slice_to(n));
+		}
+		Ok(data)
+	}
+
+	fn check_len(&self, len: uint, max: uint) -> Result<(), Error> {
+		if len > max {
+			Err(self.error(SizeLimitExceeded))
+		} else {
+			Ok(())
+		}
+	}
+
+	/* Called when a container is opened; the matching `leave` must follow
+	 * whether or not its elements were read successfully. */
+	fn enter(&mut self, len: uint) -> Result<(), Error> {
+		try!(self.check_len(len, self.limits.max_container_len));
+		if self.depth >= self.limits.max_depth {
+			return Err(self.error(DepthLimitExceeded));
+		}
+		self.depth += 1;
+		Ok(())
+	}
+
+	fn leave(&mut self) {
+		self.depth -= 1;
+	}
+
+	fn read_byte(&mut self) -> Result<u8, Error> {
+		let mut buf = [0u8, ..1];
+		try!(self.fill(buf));
+		Ok(buf[0])
+	}
+
+	fn read_be16(&mut self) -> Result<u16, Error> {
+		let mut buf = [0u8, ..2];
+		try!(self.fill(buf));
+		Ok((buf[0] as u16 << 8) | buf[1] as u16)
+	}
+
+	fn read_be32(&mut self) -> Result<u32, Error> {
+		let mut buf = [0u8, ..4];
+		try!(self.fill(buf));
+		let mut x : u32 = 0;
+		for b in buf.iter() {
+			x = (x << 8) | *b as u32;
+		}
+		Ok(x)
+	}
+
+	fn read_be64(&mut self) -> Result<u64, Error> {
+		let mut buf = [0u8, ..8];
+		try!(self.fill(buf));
+		let mut x : u64 = 0;
+		for b in buf.iter() {
+			x = (x << 8) | *b as u64;
+		}
+		Ok(x)
+	}
+
+	/* Called at the start of each value. */
+	fn recover(&mut self) {
+		if self.failed.get() {
+			self.failed.set(false);
+			match self.profile {
+				Some(ref mut profile) => profile.reset(),
+				None => {}
+			}
+		}
+	}
+
+	fn read_tag(&mut self) -> Result<u8, Error> {
+		self.recover();
+		self.tag = None;
+		self.start = self.offset;
+		let tag = try!(self.read_byte());
+		self.tag = Some(tag);
+		let allowed = match self.profile {
+			Some(ref mut profile) => profile.check(tag),
+			None => true
+		};
+		if !allowed {
+			return Err(self.error(ProfileViolation));
+		}
+		Ok(tag)
+	}
+
+	/* Called once the length of a container is read; `len` counts pairs
+	 * for a map. */
+	fn opened(&mut self, len: uint, map: bool) {
+		match self.profile {
+			Some(ref mut profile) => profile.open(len, map),
+			None => {}
+		}
+	}
+
+	/* Reads the next tag without consuming it; the following `read_tag`
+	 * will return it again. */
+	fn peek_tag(&mut self) -> Result<u8, Error> {
+		self.recover();
+		self.tag = None;
+		self.start = self.offset;
+		try!(self.peek(1));
+		let tag = self.lookahead[0];
+		self.tag = Some(tag);
+		Ok(tag)
+	}
+
+	/* Reads and throws away `len` bytes. */
+	fn discard(&mut self, len: uint) -> Result<(), Error> {
+		let mut chunk = [0u8, ..ReserveLimit];
+		let mut left = len;
+		while left > 0 {
+			let n = cmp::min(left, chunk.len());
+			try!(self.fill(chunk.mut_slice_to(n)));
+			left -= n;
+		}
+		Ok(())
 	}
 }
 
@@ -60,34 +500,128 @@ impl<'a> Decoder<'a> {
 		let x = self.try_read();
 		match x {
 			Ok(y) => y,
-			Err(e) => fail!(e)
+			Err(e) => fail!(e.to_str())
 		}
 	}
 
+	fn read_array(&mut self, elements: uint) -> Result<Value, Error> {
+		try!(self.enter(elements));
+		self.opened(elements, false);
+		let result = self.read_elements(elements);
+		self.leave();
+		result
+	}
+
 	#[allow(experimental)]
-	fn read_array(&mut self, elements: uint) -> Result<Value, &'static str> {
+	fn read_elements(&mut self, elements: uint) -> Result<Value, Error> {
 		/* reserve space for each value */
-		let mut accum : ~[Value] = vec::with_capacity(elements);
+		let mut accum : ~[Value] = vec::with_capacity(cmp::min(elements, ReserveLimit));
 		/* for each element we are to read */
 		let mut current = 0;
 		while (current < elements) {
-			let next_candidate = self.try_read();
-			match next_candidate {
-				Ok(x) => accum.push(x),
-				Err(_) => return next_candidate
-			}
+			accum.push(try!(self.try_read()));
 			current += 1;
 		}
 		/* return decoded array */
 		Ok(Array(accum))
 	}
 
-	fn read_str(&mut self, len: uint) -> Result<Value, &'static str> {
-		let data = self.reader.read_bytes(len);
-		if data.len() == len {
-			Ok(String(str::from_utf8_owned(data)))
-		} else {
-			Err(ErrInsufficientData)
+	fn read_map(&mut self, pairs: uint) -> Result<Value, Error> {
+		try!(self.enter(pairs));
+		self.opened(pairs, true);
+		let result = self.read_pairs(pairs);
+		self.leave();
+		result
+	}
+
+	#[allow(experimental)]
+	fn read_pairs(&mut self, pairs: uint) -> Result<Value, Error> {
+		/* reserve space for each key/value pair */
+		let mut accum : ~[(Value, Value)] = vec::with_capacity(cmp::min(pairs, ReserveLimit));
+		/* for each pair we are to read */
+		let mut current = 0;
+		while (current < pairs) {
+			let key = try!(self.try_read());
+			let value = try!(self.try_read());
+			accum.push((key, value));
+			current += 1;
+		}
+		/* return decoded map */
+		Ok(Map(accum))
+	}
+
+	fn read_str(&mut self, len: uint) -> Result<Value, Error> {
+		try!(self.check_len(len, self.limits.max_str_len));
+		let start = self.offset;
+		let data = try!(self.read_chunked(len));
+		let bad = match utf8::error_at(data) {
+			Some(i) => i,
+			None => return Ok(String(str::from_utf8_owned(data)))
+		};
+		self.invalid_utf8 = true;
+		match self.utf8_policy {
+			Utf8Strict => {
+				self.failed.set(true);
+				Err(Error::new(InvalidUtf8, start + bad as u64, self.tag))
+			}
+			/* a binary is never allowed by the profile */
+			Utf8AsBinary if self.profile.is_some() => Err(self.error(ProfileViolation)),
+			Utf8Lossy => Ok(String(utf8::from_lossy(data))),
+			Utf8AsBinary => Ok(Binary(data))
+		}
+	}
+
+	fn read_bin(&mut self, len: uint) -> Result<Value, Error> {
+		try!(self.check_len(len, self.limits.max_bin_len));
+		Ok(Binary(try!(self.read_chunked(len))))
+	}
+
+	/* Reads the payload length of an extension whose tag was just read. */
+	fn read_ext_len(&mut self, tag: u8) -> Result<uint, Error> {
+		match tag {
+			encoded_type::Fixext1 => Ok(1),
+			encoded_type::Fixext2 => Ok(2),
+			encoded_type::Fixext4 => Ok(4),
+			encoded_type::Fixext8 => Ok(8),
+			encoded_type::Fixext16 => Ok(16),
+			encoded_type::Ext8 => Ok(try!(self.read_byte()) as uint),
+			encoded_type::Ext16 => Ok(try!(self.read_be16()) as uint),
+			encoded_type::Ext32 => Ok(try!(self.read_be32()) as uint),
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	fn read_raw_ext(&mut self, len: uint) -> Result<(i8, ~[u8]), Error> {
+		try!(self.check_len(len, self.limits.max_ext_len));
+		let type_code = try!(self.read_byte()) as i8;
+		Ok((type_code, try!(self.read_chunked(len))))
+	}
+
+	/* Extensions with a registered handler, and of the types MsgPack
+	 * defines, are returned as their own values; the rest are left as they
+	 * are unless the registry rejects them. */
+	fn read_ext(&mut self, len: uint) -> Result<Value, Error> {
+		let (type_code, data) = try!(self.read_raw_ext(len));
+		let registry = self.registry.clone();
+		match registry {
+			Some(ref r) if r.borrow().handles(type_code) => {
+				return match r.borrow().unpack(type_code, data) {
+					Ok(x) => Ok(Custom(x)),
+					Err(kind) => Err(self.error(kind))
+				};
+			}
+			_ => {}
+		}
+		if type_code == timestamp::TypeCode {
+			return match Timestamp::from_bytes(data) {
+				Some(t) => Ok(Time(t)),
+				None => Err(self.error(InvalidTimestamp))
+			};
+		}
+		match registry {
+			Some(ref r) if r.borrow().policy() == RejectUnknown =>
+				Err(self.error(UnknownExtension)),
+			_ => Ok(Extension(type_code, data))
 		}
 	}
 
@@ -95,169 +629,870 @@ impl<'a> Decoder<'a> {
 	/// position. It is up to the user to store the current position and
 	/// perform any seeking if they desire. (Seeking may not always be
 	/// desirable, and the reader may not support arbitrary seeking; so this
-	/// is left to the user who will know more about what they want.)
-	#[experimental] // experimental until the TODOs are fixed.
-	pub fn try_read(&mut self) -> Result<Value, &'static str> {
-		let tag = self.reader.read_u8();
-		match tag {
-			// fixed numbers (7-bit)
-			x if ((x & 0x80) == 0) => { Ok(Unsigned((x & 0x7F) as u64)) }
-			// fixed negative values (5-bit)
-			x if ((x & 0xE0) == 0xE0) => {
-				let x : i8 = unsafe { cast::transmute( x ) };
-				Ok(Signed(x as i64) )
+	/// is left to the user who will know more about what they want.) The
+	/// returned error carries the number of bytes consumed before the
+	/// failure was found.
+	pub fn try_read(&mut self) -> Result<Value, Error> {
+		self.top_level(|d| {
+			let tag = try!(d.read_tag());
+			d.read_body(tag)
+		})
+	}
+
+	/* Runs one of the public readers; the outermost of them starts a new
+	 * top-level value. */
+	fn top_level<T>(&mut self, f: |&mut Decoder<'a>| -> Result<T, Error>) -> Result<T, Error> {
+		if self.nesting == 0 {
+			self.invalid_utf8 = false;
+		}
+		self.nesting += 1;
+		let result = f(self);
+		self.nesting -= 1;
+		result
+	}
+
+	/// Reads the next value along with the exact encoding of it and each of
+	/// its elements. See the `lossless` module.
+	pub fn read_lossless(&mut self) -> Result<lossless::Value, Error> {
+		self.top_level(|d| d.read_lossless_value())
+	}
+
+	fn read_lossless_value(&mut self) -> Result<lossless::Value, Error> {
+		let tag = try!(self.read_tag());
+		let data = match tag {
+			x if ((x & 0xF0) == 0x90) => try!(self.read_lossless_array((x & 0x0F) as uint)),
+			x if ((x & 0xF0) == 0x80) => try!(self.read_lossless_map((x & 0x0F) as uint)),
+			encoded_type::Array16 => {
+				let len = try!(self.read_be16());
+				try!(self.read_lossless_array(len as uint))
 			}
-			// fixed string (5-bit, up to 31 elements)
-			x if ((x & 0xE0) == 0xA0) => {
-				self.read_str((x & 0x1F) as uint)
+			encoded_type::Array32 => {
+				let len = try!(self.read_be32());
+				try!(self.read_lossless_array(len as uint))
 			}
-			// fixed array (4-bit, up to 15 elements)
-			x if ((x & 0xF0) == 0x90) => {
-				let len : u8 = x & 0xF;
-				self.read_array(len as uint)
+			encoded_type::Map16 => {
+				let len = try!(self.read_be16());
+				try!(self.read_lossless_map(len as uint))
 			}
-			// fixed map (4-bit, up to 15 elements)
-			x if ((x & 0xF0) == 0x80) => { fail!("TODO.") }
-
-			encoded_type::True => { Ok(Boolean(true)) }
-			encoded_type::False  => { Ok(Boolean(false)) }
-			encoded_type::Nil => { Ok(Nil) }
-			encoded_type::Array16  => {
-				let len = self.reader.read_be_u16();
-				self.read_array(len as uint)
+			encoded_type::Map32 => {
+				let len = try!(self.read_be32());
+				try!(self.read_lossless_map(len as uint))
 			}
-			encoded_type::Array32  => {
-				let len = self.reader.read_be_u32();
-				self.read_array(len as uint)
+			/* strings keep their bytes as they were written, whatever the
+			 * UTF-8 policy */
+			x if ((x & 0xE0) == 0xA0) => try!(self.read_lossless_str((x & 0x1F) as uint)),
+			encoded_type::Str8 => {
+				let len = try!(self.read_byte());
+				try!(self.read_lossless_str(len as uint))
 			}
-			encoded_type::Bin8  => {
-				let len = self.reader.read_u8() as uint;
-				let data : ~[u8] = self.reader.read_bytes(len);
-				if data.len() != len {
-					Err(ErrInsufficientData)
-				} else {
-					Ok(Binary(data))
-				}
+			encoded_type::Str16 => {
+				let len = try!(self.read_be16());
+				try!(self.read_lossless_str(len as uint))
 			}
-			encoded_type::Bin16  => {
-				let len = self.reader.read_u8() as uint;
-				let data : ~[u8] = self.reader.read_bytes(len);
-				if data.len() != len {
-					Err(ErrInsufficientData)
-				} else {
-					Ok(Binary(data))
-				}
+			encoded_type::Str32 => {
+				let len = try!(self.read_be32());
+				try!(self.read_lossless_str(len as uint))
 			}
-			encoded_type::Bin32  => {
-				let len = self.reader.read_u8() as uint;
-				let data : ~[u8] = self.reader.read_bytes(len);
-				if data.len() != len {
-					Err(ErrInsufficientData)
-				} else {
-					Ok(Binary(data))
-				}
+			/* extensions keep their payload as it was written */
+			encoded_type::Ext8 | encoded_type::Ext16 | encoded_type::Ext32 |
+				encoded_type::Fixext1 | encoded_type::Fixext2 | encoded_type::Fixext4 |
+				encoded_type::Fixext8 | encoded_type::Fixext16 => {
+				let len = try!(self.read_ext_len(tag));
+				let (type_code, data) = try!(self.read_raw_ext(len));
+				lossless::Scalar(Extension(type_code, data))
 			}
-			encoded_type::Ext8  => {
-				let len = self.reader.read_u8() as uint;
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(len);
-				if data.len() == len {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
-				}
+			_ => lossless::Scalar(try!(self.read_body(tag)))
+		};
+		Ok(lossless::Value { tag: tag, data: data })
+	}
+
+	fn read_lossless_str(&mut self, len: uint) -> Result<lossless::Data, Error> {
+		try!(self.check_len(len, self.limits.max_str_len));
+		let data = try!(self.read_chunked(len));
+		match utf8::error_at(data) {
+			None => Ok(lossless::Scalar(String(str::from_utf8_owned(data)))),
+			Some(_) => {
+				self.invalid_utf8 = true;
+				Ok(lossless::Scalar(Binary(data)))
 			}
-			encoded_type::Ext16  => {
-				let len = self.reader.read_be_u16() as uint;
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(len);
-				if data.len() == len {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
-				}
+		}
+	}
+
+	fn read_lossless_array(&mut self, elements: uint) -> Result<lossless::Data, Error> {
+		try!(self.enter(elements));
+		self.opened(elements, false);
+		let result = self.read_lossless_elements(elements);
+		self.leave();
+		result
+	}
+
+	fn read_lossless_elements(&mut self, elements: uint) -> Result<lossless::Data, Error> {
+		let mut accum : ~[lossless::Value] = vec::with_capacity(cmp::min(elements, ReserveLimit));
+		for _ in range(0, elements) {
+			accum.push(try!(self.read_lossless_value()));
+		}
+		Ok(lossless::Array(accum))
+	}
+
+	fn read_lossless_map(&mut self, pairs: uint) -> Result<lossless::Data, Error> {
+		try!(self.enter(pairs));
+		self.opened(pairs, true);
+		let result = self.read_lossless_pairs(pairs);
+		self.leave();
+		result
+	}
+
+	fn read_lossless_pairs(&mut self, pairs: uint) -> Result<lossless::Data, Error> {
+		let mut accum : ~[(lossless::Value, lossless::Value)] =
+			vec::with_capacity(cmp::min(pairs, ReserveLimit));
+		for _ in range(0, pairs) {
+			let key = try!(self.read_lossless_value());
+			let value = try!(self.read_lossless_value());
+			accum.push((key, value));
+		}
+		Ok(lossless::Map(accum))
+	}
+
+	/// Reports the family of the next value and its length, without
+	/// consuming it. The length is the number of elements of an array, pairs
+	/// of a map, or bytes of a string, binary or extension payload; it is
+	/// zero for every other family. The whole header is read ahead, so a
+	/// header cut short gives `UnexpectedEof`.
+	pub fn peek_type(&mut self) -> Result<(ValueType, uint), Error> {
+		let tag = try!(self.peek_tag());
+		let (header, at) = {
+			let mut ahead = Ahead { decoder: self, at: 1 };
+			let header = try!(header::read(&mut ahead, tag));
+			(header, ahead.at)
+		};
+		Ok(match header {
+			header::Unsigned(_) | header::Signed(_) => (IntegerType, 0),
+			header::Float32(_) | header::Float64(_) => (FloatType, 0),
+			header::Boolean(_) => (BooleanType, 0),
+			header::Nil => (NilType, 0),
+			header::Str(len) => (StringType, len),
+			header::Bin(len) => (BinaryType, len),
+			header::Array(len) => (ArrayType, len),
+			header::Map(len) => (MapType, len),
+			/* the type code comes straight after the header */
+			header::Ext(len) => {
+				let type_code = try!(self.peek_be(at, 1)) as u8 as i8;
+				(ExtensionType(type_code), len)
 			}
-			encoded_type::Ext32  => {
-				let len = self.reader.read_be_u32() as uint;
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(len);
-				if data.len() == len {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
-				}
+			header::Reserved => return Err(self.error(ReservedTag))
+		})
+	}
+
+	/* The number of values `skip` must go on to skip for a container. */
+	fn skip_container(&mut self, len: uint, map: bool) -> uint {
+		self.opened(len, map);
+		if map { 2 * len } else { len }
+	}
+
+	/// Advances past the next value, including everything nested inside
+	/// it, without building or allocating anything for it. Only the
+	/// `max_total_bytes` limit applies, as nothing is kept.
+	pub fn skip(&mut self) -> Result<(), Error> {
+		/* values still to be skipped; containers add their elements */
+		let mut remaining : uint = 1;
+		while remaining > 0 {
+			remaining -= 1;
+			let tag = try!(self.read_tag());
+			match try!(header::read(self, tag)) {
+				header::Unsigned(_) | header::Signed(_) | header::Float32(_) |
+					header::Float64(_) | header::Boolean(_) | header::Nil => {},
+				header::Str(len) | header::Bin(len) => try!(self.discard(len)),
+				/* the payload is preceded by the type code */
+				header::Ext(len) => try!(self.discard(1 + len)),
+				header::Array(len) => remaining += self.skip_container(len, false),
+				header::Map(len) => remaining += self.skip_container(len, true),
+				header::Reserved => return Err(self.error(ReservedTag))
 			}
-			encoded_type::Fixext1  => {
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(1);
-				if data.len() == 1 {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
+		}
+		Ok(())
+	}
+
+	/// Reads a value of any `Decodable` type from the stream. The same
+	/// caveat as `try_read` applies: a failed read is not rewound.
+	pub fn read_as<T: Decodable>(&mut self) -> Result<T, Error> {
+		self.top_level(|d| Decodable::decode(d))
+	}
+
+	/* Reads the remainder of a value whose tag has already been consumed. */
+	fn read_body(&mut self, tag: u8) -> Result<Value, Error> {
+		match try!(header::read(self, tag)) {
+			header::Unsigned(x) => Ok(Unsigned(x)),
+			header::Signed(x) => Ok(Signed(x)),
+			header::Float32(x) => Ok(Float32(x)),
+			header::Float64(x) => Ok(Float64(x)),
+			header::Boolean(x) => Ok(Boolean(x)),
+			header::Nil => Ok(Nil),
+			header::Str(len) => self.read_str(len),
+			header::Bin(len) => self.read_bin(len),
+			header::Ext(len) => self.read_ext(len),
+			header::Array(len) => self.read_array(len),
+			header::Map(len) => self.read_map(len),
+			header::Reserved => Err(self.error(ReservedTag))
+		}
+	}
+}
+
+impl<'a> header::Source for Decoder<'a> {
+	fn read_be(&mut self, width: uint) -> Result<u64, Error> {
+		let mut buf = [0u8, ..8];
+		try!(self.fill(buf.mut_slice_to(width)));
+		Ok(header::from_be(buf.slice_to(width)))
+	}
+}
+
+/* Reads a header out of the lookahead, for `peek_type`, leaving the bytes
+ * there to be read again. `at` is how far in to the lookahead it has got. */
+struct Ahead<'b, 'a> {
+	decoder: &'b mut Decoder<'a>,
+	at: uint
+}
+
+impl<'b, 'a> header::Source for Ahead<'b, 'a> {
+	fn read_be(&mut self, width: uint) -> Result<u64, Error> {
+		let end = self.at + width;
+		try!(self.decoder.peek(end));
+		let x = header::from_be(self.decoder.lookahead.slice(self.at, end));
+		self.at = end;
+		Ok(x)
+	}
+}
+
+/// Methods which read a single value, or just the header of one, straight
+/// in to a Rust type without building a `Value`. Each consumes the tag of
+/// the next value and fails with `TypeMismatch` if it belongs to a different
+/// family than asked for; as with `try_read`, the stream is then left part
+/// way through that value. Integer readers accept any member of the int
+/// family whose value fits, and fail with `OutOfRange` otherwise.
+impl<'a> Decoder<'a> {
+	/// Reads a nil.
+	pub fn read_nil(&mut self) -> Result<(), Error> {
+		match try!(self.read_tag()) {
+			encoded_type::Nil => Ok(()),
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	/// Reads a boolean.
+	pub fn read_bool(&mut self) -> Result<bool, Error> {
+		match try!(self.read_tag()) {
+			encoded_type::True => Ok(true),
+			encoded_type::False => Ok(false),
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	/// Reads an integer which fits in a `u8`.
+	pub fn read_u8(&mut self) -> Result<u8, Error> {
+		Ok(try!(self.read_uint_max(u8::max_value as u64)) as u8)
+	}
+
+	/// Reads an integer which fits in a `u16`.
+	pub fn read_u16(&mut self) -> Result<u16, Error> {
+		Ok(try!(self.read_uint_max(u16::max_value as u64)) as u16)
+	}
+
+	/// Reads an integer which fits in a `u32`.
+	pub fn read_u32(&mut self) -> Result<u32, Error> {
+		Ok(try!(self.read_uint_max(u32::max_value as u64)) as u32)
+	}
+
+	/// Reads a non-negative integer.
+	pub fn read_u64(&mut self) -> Result<u64, Error> {
+		self.read_uint_max(u64::max_value)
+	}
+
+	/// Reads an integer which fits in an `i8`.
+	pub fn read_i8(&mut self) -> Result<i8, Error> {
+		Ok(try!(self.read_int_range(i8::min_value as i64, i8::max_value as i64)) as i8)
+	}
+
+	/// Reads an integer which fits in an `i16`.
+	pub fn read_i16(&mut self) -> Result<i16, Error> {
+		Ok(try!(self.read_int_range(i16::min_value as i64, i16::max_value as i64)) as i16)
+	}
+
+	/// Reads an integer which fits in an `i32`.
+	pub fn read_i32(&mut self) -> Result<i32, Error> {
+		Ok(try!(self.read_int_range(i32::min_value as i64, i32::max_value as i64)) as i32)
+	}
+
+	/// Reads an integer which fits in an `i64`.
+	pub fn read_i64(&mut self) -> Result<i64, Error> {
+		self.read_int_range(i64::min_value, i64::max_value)
+	}
+
+	/// Reads a single precision float. Double precision values are refused
+	/// rather than silently rounded.
+	pub fn read_f32(&mut self) -> Result<f32, Error> {
+		match try!(self.read_tag()) {
+			encoded_type::Float32 => match try!(self.read_body(encoded_type::Float32)) {
+				Float32(x) => Ok(x),
+				_ => unreachable!()
+			},
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	/// Reads a double precision float. Single precision values are widened,
+	/// as no precision is lost.
+	pub fn read_f64(&mut self) -> Result<f64, Error> {
+		let tag = try!(self.read_tag());
+		match tag {
+			encoded_type::Float32 | encoded_type::Float64 => {
+				match try!(self.read_body(tag)) {
+					Float32(x) => Ok(x as f64),
+					Float64(x) => Ok(x),
+					_ => unreachable!()
 				}
 			}
-			encoded_type::Fixext2  => {
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(2);
-				if data.len() == 2 {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
-				}
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	/// Reads the header of an array, returning the number of elements which
+	/// follow it.
+	pub fn read_array_len(&mut self) -> Result<uint, Error> {
+		let len = match try!(self.read_tag()) {
+			x if ((x & 0xF0) == 0x90) => (x & 0x0F) as uint,
+			encoded_type::Array16 => try!(self.read_be16()) as uint,
+			encoded_type::Array32 => try!(self.read_be32()) as uint,
+			_ => return Err(self.error(TypeMismatch))
+		};
+		try!(self.check_len(len, self.limits.max_container_len));
+		self.opened(len, false);
+		Ok(len)
+	}
+
+	/// Reads the header of a map, returning the number of key/value pairs
+	/// which follow it.
+	pub fn read_map_len(&mut self) -> Result<uint, Error> {
+		let len = match try!(self.read_tag()) {
+			x if ((x & 0xF0) == 0x80) => (x & 0x0F) as uint,
+			encoded_type::Map16 => try!(self.read_be16()) as uint,
+			encoded_type::Map32 => try!(self.read_be32()) as uint,
+			_ => return Err(self.error(TypeMismatch))
+		};
+		try!(self.check_len(len, self.limits.max_container_len));
+		self.opened(len, true);
+		Ok(len)
+	}
+
+	/// Reads the header of a string, returning the number of bytes of UTF-8
+	/// data which follow it. The data itself is read with `read_raw`.
+	pub fn read_str_len(&mut self) -> Result<uint, Error> {
+		let len = match try!(self.read_tag()) {
+			x if ((x & 0xE0) == 0xA0) => (x & 0x1F) as uint,
+			encoded_type::Str8 => try!(self.read_byte()) as uint,
+			encoded_type::Str16 => try!(self.read_be16()) as uint,
+			encoded_type::Str32 => try!(self.read_be32()) as uint,
+			_ => return Err(self.error(TypeMismatch))
+		};
+		try!(self.check_len(len, self.limits.max_str_len));
+		Ok(len)
+	}
+
+	/// Reads the header of a binary, returning the number of bytes which
+	/// follow it. The data itself is read with `read_raw`.
+	pub fn read_bin_len(&mut self) -> Result<uint, Error> {
+		let len = match try!(self.read_tag()) {
+			encoded_type::Bin8 => try!(self.read_byte()) as uint,
+			encoded_type::Bin16 => try!(self.read_be16()) as uint,
+			encoded_type::Bin32 => try!(self.read_be32()) as uint,
+			_ => return Err(self.error(TypeMismatch))
+		};
+		try!(self.check_len(len, self.limits.max_bin_len));
+		Ok(len)
+	}
+
+	/// Reads the header of an extension, returning its type code and the
+	/// number of payload bytes which follow. The payload itself is read with
+	/// `read_raw`.
+	pub fn read_ext_header(&mut self) -> Result<(i8, uint), Error> {
+		let tag = try!(self.read_tag());
+		let len = try!(self.read_ext_len(tag));
+		try!(self.check_len(len, self.limits.max_ext_len));
+		let type_code = try!(self.read_byte()) as i8;
+		Ok((type_code, len))
+	}
+
+	/// Reads `len` bytes exactly as they appear in the stream. This is for
+	/// the contents of strings, binaries and extensions whose header was
+	/// read separately.
+	pub fn read_raw(&mut self, len: uint) -> Result<~[u8], Error> {
+		self.read_chunked(len)
+	}
+
+	/// Reads an extension and unpacks it with the registry, giving back the
+	/// application value. Fails with `UnknownExtension` if there is no
+	/// registry or no handler for the type, and with `TypeMismatch` if the
+	/// handler gives something other than a `T`.
+	pub fn read_extension<T: 'static>(&mut self) -> Result<T, Error> {
+		let (type_code, len) = try!(self.read_ext_header());
+		let data = try!(self.read_raw(len));
+		let registry = match self.registry {
+			Some(ref r) => r.clone(),
+			None => return Err(self.error(UnknownExtension))
+		};
+		let object = match registry.borrow().unpack(type_code, data) {
+			Ok(x) => x,
+			Err(kind) => return Err(self.error(kind))
+		};
+		match object.take::<T>() {
+			Ok(x) => Ok(x),
+			Err(_) => Err(self.error(TypeMismatch))
+		}
+	}
+
+	/* Reads any member of the int family as `Signed` or `Unsigned`. */
+	fn read_integer(&mut self) -> Result<Value, Error> {
+		let tag = try!(self.read_tag());
+		match tag {
+			// positive and negative fixnums
+			x if ((x & 0x80) == 0) || ((x & 0xE0) == 0xE0) => self.read_body(tag),
+			// uint 8 through int 64
+			0xCC .. 0xD3 => self.read_body(tag),
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	fn read_uint_max(&mut self, max: u64) -> Result<u64, Error> {
+		match try!(self.read_integer()) {
+			Unsigned(x) if x <= max => Ok(x),
+			Signed(x) if x >= 0 && (x as u64) <= max => Ok(x as u64),
+			_ => Err(self.error(OutOfRange))
+		}
+	}
+
+	fn read_int_range(&mut self, min: i64, max: i64) -> Result<i64, Error> {
+		match try!(self.read_integer()) {
+			Unsigned(x) if x <= (max as u64) => Ok(x as i64),
+			Signed(x) if x >= min && x <= max => Ok(x),
+			_ => Err(self.error(OutOfRange))
+		}
+	}
+
+	fn read_string(&mut self) -> Result<~str, Error> {
+		let tag = try!(self.read_tag());
+		match tag {
+			x if ((x & 0xE0) == 0xA0) => {},
+			encoded_type::Str8 | encoded_type::Str16 | encoded_type::Str32 => {},
+			_ => return Err(self.error(TypeMismatch))
+		}
+		match try!(self.read_body(tag)) {
+			String(x) => Ok(x),
+			/* not UTF-8, and let through as a binary by the policy */
+			_ => Err(self.error(InvalidUtf8))
+		}
+	}
+}
+
+/// A trait which indicates a type knows how to read itself back out of a
+/// decoder; the counterpart of `encoder::Encodable`. System types have this
+/// provided, your own POD structures will need to define it for themselves.
+pub trait Decodable {
+	/// Reads one value of this type from the given decoder.
+	fn decode(source: &mut Decoder) -> Result<Self, Error>;
+}
+
+macro_rules! decodable_uint(
+	($t:ty, $max:expr) => (
+		impl Decodable for $t {
+			fn decode(source: &mut Decoder) -> Result<$t, Error> {
+				Ok(try!(source.read_uint_max($max)) as $t)
 			}
-			encoded_type::Fixext4  => {
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(4);
-				if data.len() == 4 {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
-				}
+		}
+	)
+)
+
+macro_rules! decodable_int(
+	($t:ty, $min:expr, $max:expr) => (
+		impl Decodable for $t {
+			fn decode(source: &mut Decoder) -> Result<$t, Error> {
+				Ok(try!(source.read_int_range($min, $max)) as $t)
 			}
-			encoded_type::Fixext8  => {
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(8);
-				if data.len() == 8 {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
-				}
+		}
+	)
+)
+
+decodable_uint!(u8, u8::max_value as u64)
+decodable_uint!(u16, u16::max_value as u64)
+decodable_uint!(u32, u32::max_value as u64)
+decodable_uint!(u64, u64::max_value)
+decodable_uint!(uint, uint::max_value as u64)
+decodable_int!(i8, i8::min_value as i64, i8::max_value as i64)
+decodable_int!(i16, i16::min_value as i64, i16::max_value as i64)
+decodable_int!(i32, i32::min_value as i64, i32::max_value as i64)
+decodable_int!(i64, i64::min_value, i64::max_value)
+decodable_int!(int, int::min_value as i64, int::max_value as i64)
+
+impl Decodable for () {
+	fn decode(source: &mut Decoder) -> Result<(), Error> {
+		source.read_nil()
+	}
+}
+
+impl Decodable for bool {
+	fn decode(source: &mut Decoder) -> Result<bool, Error> {
+		source.read_bool()
+	}
+}
+
+impl Decodable for f32 {
+	fn decode(source: &mut Decoder) -> Result<f32, Error> {
+		source.read_f32()
+	}
+}
+
+impl Decodable for f64 {
+	fn decode(source: &mut Decoder) -> Result<f64, Error> {
+		source.read_f64()
+	}
+}
+
+impl Decodable for ~str {
+	fn decode(source: &mut Decoder) -> Result<~str, Error> {
+		source.read_string()
+	}
+}
+
+impl<T: Decodable> Decodable for ~[T] {
+	fn decode(source: &mut Decoder) -> Result<~[T], Error> {
+		let len = try!(source.read_array_len());
+		let mut accum : ~[T] = vec::with_capacity(cmp::min(len, ReserveLimit));
+		for _ in range(0, len) {
+			accum.push(try!(source.read_as::<T>()));
+		}
+		Ok(accum)
+	}
+}
+
+/* Nil decodes to `None`, mirroring `Encoder::write_option`. */
+impl<T: Decodable> Decodable for Option<T> {
+	fn decode(source: &mut Decoder) -> Result<Option<T>, Error> {
+		match try!(source.peek_tag()) {
+			encoded_type::Nil => {
+				try!(source.read_nil());
+				Ok(None)
 			}
-			encoded_type::Fixext16  => {
-				let type_code = self.reader.read_i8();
-				let data = self.reader.read_bytes(16);
-				if data.len() == 16 {
-					Ok(Extension(type_code, data))
-				} else {
-					Err(ErrInsufficientData)
+			_ => Ok(Some(try!(source.read_as::<T>())))
+		}
+	}
+}
+
+impl Decodable for Value {
+	fn decode(source: &mut Decoder) -> Result<Value, Error> {
+		source.try_read()
+	}
+}
+
+/* A char must arrive as a string holding exactly one character. */
+impl Decodable for char {
+	fn decode(source: &mut Decoder) -> Result<char, Error> {
+		let s = try!(source.read_string());
+		if s.char_len() == 1 {
+			Ok(s.char_at(0))
+		} else {
+			Err(source.error(TypeMismatch))
+		}
+	}
+}
+
+impl<T: Decodable> Decodable for ~T {
+	fn decode(source: &mut Decoder) -> Result<~T, Error> {
+		Ok(~try!(source.read_as::<T>()))
+	}
+}
+
+impl<T: Decodable + Freeze> Decodable for Rc<T> {
+	fn decode(source: &mut Decoder) -> Result<Rc<T>, Error> {
+		Ok(Rc::new(try!(source.read_as::<T>())))
+	}
+}
+
+/* Tuples must arrive as arrays of exactly their arity. */
+macro_rules! decodable_tuple(
+	($len:expr, $($name:ident),+) => (
+		impl<$($name: Decodable),+> Decodable for ($($name,)+) {
+			fn decode(source: &mut Decoder) -> Result<($($name,)+), Error> {
+				if try!(source.read_array_len()) != $len {
+					return Err(source.error(TypeMismatch));
 				}
+				Ok(($(try!(source.read_as::<$name>()),)+))
 			}
-			encoded_type::Float32  => { Ok(Float32(self.reader.read_be_f32())) }
-			encoded_type::Float64  => { Ok(Float64(self.reader.read_be_f64())) }
-			encoded_type::Int8  => { Ok(Signed(self.reader.read_i8() as i64)) }
-			encoded_type::Int16  => { Ok(Signed(self.reader.read_be_i16() as i64)) }
-			encoded_type::Int32  => { Ok(Signed(self.reader.read_be_i32() as i64)) }
-			encoded_type::Int64  => { Ok(Signed(self.reader.read_be_i64())) }
-			encoded_type::Map16  => { fail!("TODO.") }
-			encoded_type::Map32  => { fail!("TODO.") }
-			encoded_type::Str8  => {
-				let len = self.reader.read_u8() as uint;
-				self.read_str(len)
-			}
-			encoded_type::Str16  => {
-				let len = self.reader.read_be_u16() as uint;
-				self.read_str(len)
+		}
+	)
+)
+
+decodable_tuple!(1, A)
+decodable_tuple!(2, A, B)
+decodable_tuple!(3, A, B, C)
+decodable_tuple!(4, A, B, C, D)
+decodable_tuple!(5, A, B, C, D, E)
+decodable_tuple!(6, A, B, C, D, E, F)
+decodable_tuple!(7, A, B, C, D, E, F, G)
+decodable_tuple!(8, A, B, C, D, E, F, G, H)
+
+/* An array literal needs one expression per element, so each length is
+ * given one identifier per element to repeat over. */
+macro_rules! decodable_fixed_vec(
+	($len:expr, $($element:ident)*) => (
+		impl<T: Decodable> Decodable for [T, ..$len] {
+			fn decode(source: &mut Decoder) -> Result<[T, ..$len], Error> {
+				if try!(source.read_array_len()) != $len {
+					return Err(source.error(LengthMismatch));
+				}
+				Ok([$({
+					let $element = try!(source.read_as::<T>());
+					$element
+				}),*])
 			}
-			encoded_type::Str32  => {
-				let len = self.reader.read_be_u32() as uint;
-				self.read_str(len)
+		}
+	)
+)
+
+/* Counts up through the identifiers, one length at a time. */
+macro_rules! decodable_fixed_vecs(
+	($len:expr, $($element:ident)*; $next:ident $($rest:ident)*) => (
+		decodable_fixed_vec!($len, $($element)*)
+		decodable_fixed_vecs!($len + 1, $($element)* $next; $($rest)*)
+	);
+	($len:expr, $($element:ident)*;) => (
+		decodable_fixed_vec!($len, $($element)*)
+	)
+)
+
+decodable_fixed_vecs!(0, ; a b c d e f g h i j k l m n o p q r s t u v w x y z
+	aa bb cc dd ee ff)
+
+/* Where a key appears more than once, the last value wins. */
+impl<K: Decodable + Hash + Eq, V: Decodable> Decodable for HashMap<K, V> {
+	fn decode(source: &mut Decoder) -> Result<HashMap<K, V>, Error> {
+		let len = try!(source.read_map_len());
+		let mut accum = HashMap::with_capacity(cmp::min(len, ReserveLimit));
+		for _ in range(0, len) {
+			let key = try!(source.read_as::<K>());
+			let value = try!(source.read_as::<V>());
+			accum.insert(key, value);
+		}
+		Ok(accum)
+	}
+}
+
+impl<K: Decodable + TotalOrd, V: Decodable> Decodable for TreeMap<K, V> {
+	fn decode(source: &mut Decoder) -> Result<TreeMap<K, V>, Error> {
+		let len = try!(source.read_map_len());
+		let mut accum = TreeMap::new();
+		for _ in range(0, len) {
+			let key = try!(source.read_as::<K>());
+			let value = try!(source.read_as::<V>());
+			accum.insert(key, value);
+		}
+		Ok(accum)
+	}
+}
+
+impl<T: Decodable + Hash + Eq> Decodable for HashSet<T> {
+	fn decode(source: &mut Decoder) -> Result<HashSet<T>, Error> {
+		let len = try!(source.read_array_len());
+		let mut accum = HashSet::with_capacity(cmp::min(len, ReserveLimit));
+		for _ in range(0, len) {
+			accum.insert(try!(source.read_as::<T>()));
+		}
+		Ok(accum)
+	}
+}
+
+impl<T: Decodable + TotalOrd> Decodable for TreeSet<T> {
+	fn decode(source: &mut Decoder) -> Result<TreeSet<T>, Error> {
+		let len = try!(source.read_array_len());
+		let mut accum = TreeSet::new();
+		for _ in range(0, len) {
+			accum.insert(try!(source.read_as::<T>()));
+		}
+		Ok(accum)
+	}
+}
+
+/// Decodes the single value which `data` holds, and returns it along with
+/// the number of bytes it took up. Bytes left over after the value give
+/// `TrailingData`. Since the buffer may come from anywhere, this refuses
+/// input beyond `DecoderLimits::default()`; `from_bytes_with_limits` takes
+/// other limits.
+pub fn from_bytes(data: &[u8]) -> Result<(Value, uint), Error> {
+	from_bytes_with_limits(data, Default::default())
+}
+
+/// Decodes the single value which `data` holds as `from_bytes` does,
+/// refusing input which exceeds `limits`.
+pub fn from_bytes_with_limits(data: &[u8], limits: DecoderLimits) -> Result<(Value, uint), Error> {
+	let mut reader = io::mem::BufReader::new(data);
+	let mut decoder = Decoder::with_limits(&mut reader as &mut io::Reader, limits);
+	let value = try!(decoder.try_read());
+	let consumed = decoder.position() as uint;
+	if consumed != data.len() {
+		return Err(Error::new(TrailingData, consumed as u64, None));
+	}
+	Ok((value, consumed))
+}
+
+impl<'a> Decoder<'a> {
+	/// Reads the start of an enum variant as `Encoder::write_variant`
+	/// writes it, returning its name and the number of arguments which
+	/// follow.
+	pub fn read_variant(&mut self) -> Result<(~str, uint), Error> {
+		match try!(self.peek_type()) {
+			(StringType, _) => Ok((try!(self.read_string()), 0)),
+			(MapType, 1) => {
+				try!(self.read_map_len());
+				let name = try!(self.read_string());
+				let len = try!(self.read_array_len());
+				Ok((name, len))
 			}
-			encoded_type::Uint8  => { Ok(Unsigned(self.reader.read_u8() as u64)) }
-			encoded_type::Uint16  => { Ok(Unsigned(self.reader.read_be_u16() as u64)) }
-			encoded_type::Uint32  => { Ok(Unsigned(self.reader.read_be_u32() as u64)) }
-			encoded_type::Uint64  => { Ok(Unsigned(self.reader.read_be_u64())) }
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
 
-			_ => Err(ErrNoData)
+	/* Returns the index within `names` of the variant which follows. */
+	fn read_variant_index(&mut self, names: &[&str]) -> Result<uint, Error> {
+		let (name, _) = try!(self.read_variant());
+		match names.iter().position(|n| *n == name.as_slice()) {
+			Some(index) => Ok(index),
+			None => Err(self.error(TypeMismatch))
 		}
 	}
 }
+
+/* The standard serialization traits have no way to report an error, so a
+ * failed read fails the task instead. Use `Decodable` where that matters. */
+fn unwrap_read<T>(result: Result<T, Error>) -> T {
+	match result {
+		Ok(x) => x,
+		Err(e) => fail!(e.to_str())
+	}
+}
+
+/// Lets any type deriving `extra::serialize::Decodable` be read from
+/// MsgPack written by the matching `Encoder` implementation. Structure
+/// fields must arrive in declaration order under their own names. Read
+/// errors, and data which does not match the type being read, fail the
+/// task.
+impl<'a> serialize::Decoder for Decoder<'a> {
+	fn read_nil(&mut self) { unwrap_read(self.read_as::<()>()) }
+
+	fn read_uint(&mut self) -> uint { unwrap_read(self.read_as::<uint>()) }
+	fn read_u64(&mut self) -> u64 { unwrap_read(self.read_as::<u64>()) }
+	fn read_u32(&mut self) -> u32 { unwrap_read(self.read_as::<u32>()) }
+	fn read_u16(&mut self) -> u16 { unwrap_read(self.read_as::<u16>()) }
+	fn read_u8(&mut self) -> u8 { unwrap_read(self.read_as::<u8>()) }
+
+	fn read_int(&mut self) -> int { unwrap_read(self.read_as::<int>()) }
+	fn read_i64(&mut self) -> i64 { unwrap_read(self.read_as::<i64>()) }
+	fn read_i32(&mut self) -> i32 { unwrap_read(self.read_as::<i32>()) }
+	fn read_i16(&mut self) -> i16 { unwrap_read(self.read_as::<i16>()) }
+	fn read_i8(&mut self) -> i8 { unwrap_read(self.read_as::<i8>()) }
+
+	fn read_bool(&mut self) -> bool { unwrap_read(self.read_as::<bool>()) }
+	fn read_f64(&mut self) -> f64 { unwrap_read(self.read_as::<f64>()) }
+	fn read_f32(&mut self) -> f32 { unwrap_read(self.read_as::<f32>()) }
+	fn read_char(&mut self) -> char { unwrap_read(self.read_as::<char>()) }
+	fn read_str(&mut self) -> ~str { unwrap_read(self.read_as::<~str>()) }
+
+	fn read_enum<T>(&mut self, _name: &str, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+
+	fn read_enum_variant<T>(&mut self, names: &[&str],
+		f: |&mut Decoder<'a>, uint| -> T) -> T
+	{
+		let index = unwrap_read(self.read_variant_index(names));
+		f(self, index)
+	}
+
+	fn read_enum_variant_arg<T>(&mut self, _a_idx: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+
+	fn read_enum_struct_variant<T>(&mut self, names: &[&str],
+		f: |&mut Decoder<'a>, uint| -> T) -> T
+	{
+		let index = unwrap_read(self.read_variant_index(names));
+		f(self, index)
+	}
+
+	fn read_enum_struct_variant_field<T>(&mut self, _f_name: &str, _f_idx: uint,
+		f: |&mut Decoder<'a>| -> T) -> T
+	{
+		f(self)
+	}
+
+	fn read_struct<T>(&mut self, _s_name: &str, len: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		if unwrap_read(self.read_map_len()) != len {
+			fail!(self.error(TypeMismatch).to_str());
+		}
+		f(self)
+	}
+
+	fn read_struct_field<T>(&mut self, f_name: &str, _f_idx: uint,
+		f: |&mut Decoder<'a>| -> T) -> T
+	{
+		if unwrap_read(self.read_string()).as_slice() != f_name {
+			fail!(self.error(TypeMismatch).to_str());
+		}
+		f(self)
+	}
+
+	fn read_tuple<T>(&mut self, f: |&mut Decoder<'a>, uint| -> T) -> T {
+		let len = unwrap_read(self.read_array_len());
+		f(self, len)
+	}
+
+	fn read_tuple_arg<T>(&mut self, _a_idx: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+
+	fn read_tuple_struct<T>(&mut self, _s_name: &str, f: |&mut Decoder<'a>, uint| -> T) -> T {
+		let len = unwrap_read(self.read_array_len());
+		f(self, len)
+	}
+
+	fn read_tuple_struct_arg<T>(&mut self, _a_idx: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+
+	fn read_option<T>(&mut self, f: |&mut Decoder<'a>, bool| -> T) -> T {
+		match unwrap_read(self.peek_tag()) {
+			encoded_type::Nil => {
+				unwrap_read(self.read_as::<()>());
+				f(self, false)
+			}
+			_ => f(self, true)
+		}
+	}
+
+	fn read_seq<T>(&mut self, f: |&mut Decoder<'a>, uint| -> T) -> T {
+		let len = unwrap_read(self.read_array_len());
+		f(self, len)
+	}
+
+	fn read_seq_elt<T>(&mut self, _idx: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+
+	fn read_map<T>(&mut self, f: |&mut Decoder<'a>, uint| -> T) -> T {
+		let len = unwrap_read(self.read_map_len());
+		f(self, len)
+	}
+
+	fn read_map_elt_key<T>(&mut self, _idx: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+
+	fn read_map_elt_val<T>(&mut self, _idx: uint, f: |&mut Decoder<'a>| -> T) -> T {
+		f(self)
+	}
+}
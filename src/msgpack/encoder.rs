@@ -21,169 +21,349 @@
 	distribution.
  */
 
-/* XXX: Remove these when the module is done */
-#[allow(unused_imports,dead_code)];
-
+use decoder::*;
+use error::*;
+use extension::Registry;
+use lossless;
 use magic::*;
+use profile::JsonProfile;
+use timestamp;
 
+use std::any::Any;
 use std::cast;
-use std::vec;
+use std::cell::Cell;
+use std::hash::Hash;
+use std::hashmap::{HashMap, HashSet};
+use std::i64;
 use std::io;
+use std::io::Decorator;
+use std::io::mem::MemWriter;
+use std::rc::Rc;
+use std::str;
 
-static ErrTypeReserved : &'static str = "Negative types are reserved for msgpack.";
-static ErrWontFit : &'static str = "Provided type is too large to be encoded in msgpack.";
+use extra::serialize;
+use extra::treemap::{TreeMap, TreeSet};
 
 /// A utility which writes MsgPack-encoded data to an underlying `io::Writer`.
 pub struct Encoder<'a> {
-	priv writer: &'a mut io::Writer
+	priv writer: &'a mut io::Writer,
+	/* bytes written so far, for error reporting */
+	priv offset: u64,
+	/* tag of the value currently being written, for error reporting */
+	priv tag: Option<u8>,
+	/* write strings and binaries as the raw type of the old spec */
+	priv compatibility: bool,
+	priv canonical: bool,
+	priv negative_zero: NegativeZero,
+	priv profile: Option<JsonProfile>,
+	/* set whenever an error is built, so that the profile and any maps
+	 * being sorted start afresh with the next value */
+	priv failed: Cell<bool>,
+	priv registry: Option<Rc<Registry>>,
+	/* output held back from the writer for sorting, innermost last */
+	priv captures: ~[~[u8]],
+	/* maps begun with `begin_map`, innermost last */
+	priv maps: ~[MapFrame]
+}
+
+/* A map begun with `begin_map`: the number of pairs it announced and has
+ * had so far, and in canonical mode the pairs themselves, held back until
+ * they can be sorted. */
+struct MapFrame {
+	len: uint,
+	count: uint,
+	key: Option<~[u8]>,
+	pairs: ~[(~[u8], ~[u8])]
+}
+
+/// How canonical mode writes a floating point negative zero.
+#[deriving(Clone, Eq)]
+pub enum NegativeZero {
+	/// Keep the sign, so that -0.0 and 0.0 remain distinct.
+	KeepNegativeZero,
+	/// Write -0.0 as 0.0.
+	PositiveZero
 }
 
+/* The quiet NaNs which canonical mode writes for any NaN. */
+static CanonicalNan32 : u32 = 0x7FC00000;
+static CanonicalNan64 : u64 = 0x7FF8000000000000;
+
 /* Put the constructor up here */
 impl<'a> Encoder<'a> {
 	pub fn new(dst: &'a mut io::Writer) -> Encoder {
-		Encoder { writer: dst }
+		Encoder {
+			writer: dst,
+			offset: 0,
+			tag: None,
+			compatibility: false,
+			canonical: false,
+			negative_zero: KeepNegativeZero,
+			profile: None,
+			failed: Cell::new(false),
+			registry: None,
+			captures: ~[],
+			maps: ~[]
+		}
+	}
+
+	/* An encoder with the same settings, for writing part of a value
+	 * somewhere else first. */
+	fn child<'b>(&self, dst: &'b mut io::Writer) -> Encoder<'b> {
+		Encoder {
+			writer: dst,
+			offset: 0,
+			tag: None,
+			compatibility: self.compatibility,
+			canonical: self.canonical,
+			negative_zero: self.negative_zero,
+			profile: self.profile.as_ref().map(|_| JsonProfile::new()),
+			failed: Cell::new(false),
+			registry: self.registry.clone(),
+			captures: ~[],
+			maps: ~[]
+		}
+	}
+
+	/// Turns compatibility mode on or off; it is off by default. In
+	/// compatibility mode, strings and binaries are both written as the raw
+	/// type of the MsgPack spec from before 2013, which is fixstr, str16 or
+	/// str32 on the wire, so that peers using the old spec can read them.
+	/// Extensions are written as usual, as the old spec has no equivalent.
+	pub fn set_compatibility(&mut self, on: bool) {
+		self.compatibility = on;
+	}
+
+	/// Turns canonical mode on or off; it is off by default. In canonical
+	/// mode the same data is always written as the same bytes, for
+	/// applications which hash or sign what they write. Numbers, strings,
+	/// binaries and extensions always take their smallest encoding, which
+	/// the encoder does anyway. In addition, maps and sets written whole are
+	/// sorted by the encoded bytes of their keys or members, and every NaN
+	/// is written as the same quiet NaN. This covers maps written with
+	/// `begin_map`, and so structures and maps written through
+	/// `extra::serialize`, but maps streamed with `write_map_len` are written
+	/// in the order given, so sorting them is up to the caller.
+	pub fn set_canonical(&mut self, on: bool) {
+		self.canonical = on;
+	}
+
+	/// Sets how canonical mode writes a negative zero; the default is
+	/// `KeepNegativeZero`. This has no effect outside canonical mode.
+	pub fn set_negative_zero(&mut self, policy: NegativeZero) {
+		self.negative_zero = policy;
+	}
+
+	/// Turns the JSON profile on or off; it is off by default. Under the
+	/// profile, only values which convert to JSON without loss are written:
+	/// binaries, extensions, and map keys which are not strings all fail
+	/// with `ProfileViolation` before anything of them is written. Turn the
+	/// profile on or off only between top-level values.
+	pub fn set_json_profile(&mut self, on: bool) {
+		self.profile = if on { Some(JsonProfile::new()) } else { None };
+	}
+
+	/// Attaches an extension registry, or removes it given `None`. The
+	/// registry's handlers are used by `write_extension`.
+	pub fn set_registry(&mut self, registry: Option<Rc<Registry>>) {
+		self.registry = registry;
 	}
 }
 
-/* This is all low-level stuff; don't touch it */
+/* Byte-level writing; every write to the underlying stream goes through
+ * `put` so that writer errors are caught and the offset stays accurate. */
 impl<'a> Encoder<'a> {
+	/// Builds an error of the given kind at the current position. This is
+	/// for `Encodable` implementations which find a value they cannot write.
+	pub fn error(&self, kind: ErrorKind) -> Error {
+		self.failed.set(true);
+		Error::new(kind, self.offset, self.tag)
+	}
+
+	fn put(&mut self, data: &[u8]) -> Result<(), Error> {
+		let top = self.captures.len();
+		if top > 0 {
+			self.captures[top - 1].push_all(data);
+			return Ok(());
+		}
+		let result = {
+			let writer = &mut self.writer;
+			trap_io(|| writer.write(data))
+		};
+		match result {
+			Ok(()) => {
+				self.offset += data.len() as u64;
+				Ok(())
+			}
+			Err(e) => Err(self.error(IoFailure(e)))
+		}
+	}
+
 	#[inline]
-	fn write_tag(&mut self, tag: u8) {
-		self.writer.write_u8(tag)
+	fn put_u8(&mut self, t: u8) -> Result<(), Error> {
+		self.put([t])
 	}
 
 	#[inline]
-	fn write_array8(&mut self, element_count: u8) {
-		self.writer.write_u8(0x90 | (element_count & 0x0F));
+	fn put_be16(&mut self, t: u16) -> Result<(), Error> {
+		self.put([(t >> 8) as u8, t as u8])
 	}
 
 	#[inline]
-	fn write_array16(&mut self, element_count: u16) {
-		self.write_tag(encoded_type::Array16);
-		self.writer.write_be_u16(element_count)
+	fn put_be32(&mut self, t: u32) -> Result<(), Error> {
+		self.put([(t >> 24) as u8, (t >> 16) as u8, (t >> 8) as u8, t as u8])
 	}
 
 	#[inline]
-	fn write_array32(&mut self, element_count: u32) {
-		self.write_tag(encoded_type::Array32);
-		self.writer.write_be_u32(element_count);
+	fn put_be64(&mut self, t: u64) -> Result<(), Error> {
+		try!(self.put_be32((t >> 32) as u32));
+		self.put_be32(t as u32)
 	}
+}
 
+/* This is all low-level stuff; don't touch it */
+impl<'a> Encoder<'a> {
 	#[inline]
-	fn write_map_fix(&mut self, element_count: u8) {
-		self.writer.write_u8(0x80 | (element_count & 0x0F));
+	fn write_tag(&mut self, tag: u8) -> Result<(), Error> {
+		self.tag = Some(tag);
+		try!(self.check_profile(tag));
+		self.put_u8(tag)
+	}
+
+	/* Forgets the state of a value which failed part way through. */
+	fn recover(&mut self) {
+		if self.failed.get() {
+			self.failed.set(false);
+			match self.profile {
+				Some(ref mut profile) => profile.reset(),
+				None => {}
+			}
+			self.captures = ~[];
+			self.maps = ~[];
+		}
+	}
+
+	fn check_profile(&mut self, tag: u8) -> Result<(), Error> {
+		self.recover();
+		let allowed = match self.profile {
+			Some(ref mut profile) => profile.check(tag),
+			None => true
+		};
+		if allowed {
+			Ok(())
+		} else {
+			Err(self.error(ProfileViolation))
+		}
+	}
+
+	/* Called once the header of a container is written; `len` counts pairs
+	 * for a map. */
+	fn opened(&mut self, len: uint, map: bool) {
+		match self.profile {
+			Some(ref mut profile) => profile.open(len, map),
+			None => {}
+		}
 	}
 
 	#[inline]
-	fn write_map16(&mut self, element_count: u16) {
-		self.write_tag(encoded_type::Map16);
-		self.writer.write_be_u16(element_count)
+	fn write_array8(&mut self, element_count: u8) -> Result<(), Error> {
+		self.write_tag(0x90 | (element_count & 0x0F))
 	}
 
 	#[inline]
-	fn write_map32(&mut self, element_count: u32) {
-		self.write_tag(encoded_type::Map32);
-		self.writer.write_be_u32(element_count);
+	fn write_array16(&mut self, element_count: u16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Array16));
+		self.put_be16(element_count)
 	}
 
 	#[inline]
-	fn write_fix_num(&mut self, t: u8) {
-		self.writer.write_u8(t & 0x7F)
+	fn write_array32(&mut self, element_count: u32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Array32));
+		self.put_be32(element_count)
 	}
 
-	fn write_fix_neg(&mut self, t: i8) {
-		let x : u8 = unsafe { cast::transmute(t) };
-		self.writer.write_u8(x)
+	#[inline]
+	fn write_map_fix(&mut self, element_count: u8) -> Result<(), Error> {
+		self.write_tag(0x80 | (element_count & 0x0F))
 	}
 
-	fn write_str_fix(&mut self, data: &str) {
-		let x : u8 = 0xA0 | ((data.len() as u8) & 0x1F);
-		self.writer.write_u8(x);
-		self.writer.write(data.as_bytes())
+	#[inline]
+	fn write_map16(&mut self, element_count: u16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Map16));
+		self.put_be16(element_count)
 	}
 
-	fn write_str8(&mut self, data: &str) {
-		self.write_tag(encoded_type::Str8);
-		self.writer.write_u8(data.len() as u8);
-		self.writer.write(data.as_bytes())
+	#[inline]
+	fn write_map32(&mut self, element_count: u32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Map32));
+		self.put_be32(element_count)
 	}
 
-	fn write_str16(&mut self, data: &str) {
-		self.write_tag(encoded_type::Str16);
-		self.writer.write_be_u16(data.len() as u16);
-		self.writer.write(data.as_bytes())
+	#[inline]
+	fn write_fix_num(&mut self, t: u8) -> Result<(), Error> {
+		self.write_tag(t & 0x7F)
 	}
 
-	fn write_str32(&mut self, data: &str) {
-		self.write_tag(encoded_type::Str32);
-		self.writer.write_be_u32(data.len() as u32);
-		self.writer.write(data.as_bytes())
+	fn write_fix_neg(&mut self, t: i8) -> Result<(), Error> {
+		let x : u8 = unsafe { cast::transmute(t) };
+		self.write_tag(x)
 	}
 
-	fn write_ext8(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Ext8);
-		self.writer.write_i8(user_type);
-		self.writer.write_u8(data.len() as u8);
+	fn write_str_fix(&mut self, len: u8) -> Result<(), Error> {
+		self.write_tag(0xA0 | (len & 0x1F))
 	}
 
-	fn write_ext16(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Ext16);
-		self.writer.write_i8(user_type);
-		self.writer.write_be_u16(data.len() as u16);
+	fn write_str8(&mut self, len: u8) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Str8));
+		self.put_u8(len)
 	}
 
-	fn write_ext32(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Ext32);
-		self.writer.write_i8(user_type);
-		self.writer.write_be_u32(data.len() as u32);
+	fn write_str16(&mut self, len: u16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Str16));
+		self.put_be16(len)
 	}
 
-	fn write_fixext1(&mut self, user_type: i8, data: u8) {
-		self.write_tag(encoded_type::Fixext1);
-		self.writer.write_i8(user_type);
-		self.writer.write_u8(data);
+	fn write_str32(&mut self, len: u32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Str32));
+		self.put_be32(len)
 	}
 
-	fn write_fixext2(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Fixext2);
-		self.writer.write_i8(user_type);
-		self.writer.write(data.slice_to(2))
+	fn write_ext8(&mut self, user_type: i8, len: u8) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Ext8));
+		try!(self.put_u8(len));
+		self.put_u8(user_type as u8)
 	}
 
-	fn write_fixext4(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Fixext4);
-		self.writer.write_i8(user_type);
-		self.writer.write(data.slice_to(4))
+	fn write_ext16(&mut self, user_type: i8, len: u16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Ext16));
+		try!(self.put_be16(len));
+		self.put_u8(user_type as u8)
 	}
 
-	fn write_fixext8(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Fixext8);
-		self.writer.write_i8(user_type);
-		self.writer.write(data.slice_to(8))
+	fn write_ext32(&mut self, user_type: i8, len: u32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Ext32));
+		try!(self.put_be32(len));
+		self.put_u8(user_type as u8)
 	}
 
-	fn write_fixext16(&mut self, user_type: i8, data: &[u8]) {
-		self.write_tag(encoded_type::Fixext16);
-		self.writer.write_i8(user_type);
-		self.writer.write(data.slice_to(16))
+	fn write_fixext(&mut self, tag: u8, user_type: i8) -> Result<(), Error> {
+		try!(self.write_tag(tag));
+		self.put_u8(user_type as u8)
 	}
 
-	fn write_bin8(&mut self, data: &[u8]) {
-		self.write_tag(encoded_type::Bin8);
-		self.writer.write_u8(data.len() as u8);
-		self.writer.write(data)
+	fn write_bin8(&mut self, len: u8) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Bin8));
+		self.put_u8(len)
 	}
 
-	fn write_bin16(&mut self, data: &[u8]) {
-		self.write_tag(encoded_type::Bin16);
-		self.writer.write_be_u16(data.len() as u16);
-		self.writer.write(data)
+	fn write_bin16(&mut self, len: u16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Bin16));
+		self.put_be16(len)
 	}
 
-	fn write_bin32(&mut self, data: &[u8]) {
-		self.write_tag(encoded_type::Bin32);
-		self.writer.write_be_u32(data.len() as u32);
-		self.writer.write(data)
+	fn write_bin32(&mut self, len: u32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Bin32));
+		self.put_be32(len)
 	}
 }
 
@@ -191,66 +371,66 @@ impl<'a> Encoder<'a> {
 
 impl<'a> Encoder<'a> {
  	#[inline]
-	fn write_nil(&mut self) {
+	fn write_nil(&mut self) -> Result<(), Error> {
 		self.write_tag(encoded_type::Nil)
 	}
 
 	#[inline]
-	fn write_false(&mut self) {
+	fn write_false(&mut self) -> Result<(), Error> {
 		self.write_tag(encoded_type::False)
 	}
 
 	#[inline]
-	fn write_true(&mut self) {
+	fn write_true(&mut self) -> Result<(), Error> {
 		self.write_tag(encoded_type::True)
 	}
 
 	#[inline]
-	fn write_i8(&mut self, t: i8) {
-		self.write_tag(encoded_type::Int8);
-		self.writer.write_i8(t)
+	fn write_i8(&mut self, t: i8) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Int8));
+		self.put_u8(t as u8)
 	}
 
 	#[inline]
-	fn write_u8(&mut self, t: u8) {
-		self.write_tag(encoded_type::Uint8);
-		self.writer.write_u8(t)
+	fn write_u8(&mut self, t: u8) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Uint8));
+		self.put_u8(t)
 	}
 
 	#[inline]
-	fn write_i16(&mut self, t: i16) {
-		self.write_tag(encoded_type::Int16);
-		self.writer.write_be_i16(t)
+	fn write_i16(&mut self, t: i16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Int16));
+		self.put_be16(t as u16)
 	}
 
 	#[inline]
-	fn write_u16(&mut self, t: u16) {
-		self.write_tag(encoded_type::Uint16);
-		self.writer.write_be_u16(t)
+	fn write_u16(&mut self, t: u16) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Uint16));
+		self.put_be16(t)
 	}
 
 	#[inline]
-	fn write_i32(&mut self, t: i32) {
-		self.write_tag(encoded_type::Int32);
-		self.writer.write_be_i32(t)
+	fn write_i32(&mut self, t: i32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Int32));
+		self.put_be32(t as u32)
 	}
 
 	#[inline]
-	fn write_u32(&mut self, t: u32) {
-		self.write_tag(encoded_type::Uint32);
-		self.writer.write_be_u32(t)
+	fn write_u32(&mut self, t: u32) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Uint32));
+		self.put_be32(t)
 	}
 
 	#[inline]
-	fn write_i64(&mut self, t: i64) {
-		self.write_tag(encoded_type::Int64);
-		self.writer.write_be_i64(t)
+	fn write_i64(&mut self, t: i64) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Int64));
+		self.put_be64(t as u64)
 	}
 
 	#[inline]
-	fn write_u64(&mut self, t: u64) {
-		self.write_tag(encoded_type::Uint64);
-		self.writer.write_be_u64(t)
+	fn write_u64(&mut self, t: u64) -> Result<(), Error> {
+		try!(self.write_tag(encoded_type::Uint64));
+		self.put_be64(t)
 	}
 }
 
@@ -260,24 +440,26 @@ impl<'a> Encoder<'a> {
 /// will need to define this for themselves.
 pub trait Encodable {
 	/// Performs the necessary encoding on a given high layer encoding target.
-	fn encode (&self, destination: &mut Encoder);
+	/// Errors from the encoder should be passed back to the caller.
+	fn encode (&self, destination: &mut Encoder) -> Result<(), Error>;
 }
 
 /// Methods which write encoded data in to the stream. Writing functions will
 /// select the appropriate header tags to accomidate the amount of data being
 /// written, and will ensure both a minimal and correct amount of data is
-/// output to the stream.
+/// output to the stream. Should writing fail, the stream may contain a
+/// partially written value.
 impl<'a> Encoder<'a> {
 	/// Writes an `Encodable` type to the underlying stream.
 	#[inline]
-	pub fn write<T: Encodable>(&mut self, t: &T) {
+	pub fn write<T: Encodable>(&mut self, t: &T) -> Result<(), Error> {
 		t.encode(self)
 	}
 
 	/// Writes a signed integer to the stream, using the least number of bytes
 	/// possible. Note that if the provided value is zero or greater, it will
 	/// be encoded on the wire as an _unsigned_ number to save space.
-	pub fn write_int(&mut self, t: i64) {
+	pub fn write_int(&mut self, t: i64) -> Result<(), Error> {
 		// If this value is positive, safely short-circuit and treat as an
 		// unsigned value.
 		if (t >= 0) {
@@ -286,19 +468,18 @@ impl<'a> Encoder<'a> {
 			// Negative value, so now we need to find which type we can cram
 			// this in.
 			match t {
-				x if x >= -15 => self.write_fix_neg(t as i8),
-				x if x >= -127 => self.write_i8(t as i8),
-				x if x >= -32767 => self.write_i16(t as i16),
-				x if x >= -2147483647 => self.write_i32(t as i32),
-				x if x >= -9223372036854775808 => self.write_i64(t as i64),
-				_ => { fail!(ErrWontFit) }
+				x if x >= -32 => self.write_fix_neg(t as i8),
+				x if x >= -128 => self.write_i8(t as i8),
+				x if x >= -32768 => self.write_i16(t as i16),
+				x if x >= -2147483648 => self.write_i32(t as i32),
+				_ => self.write_i64(t)
 			}
 		}
 	}
 
 	/// Writes an unsigned integer to the stream, using the least number of
 	/// bytes possible.
-	pub fn write_uint(&mut self, t: u64) {
+	pub fn write_uint(&mut self, t: u64) -> Result<(), Error> {
 		match t {
 			x if x < 128 => self.write_fix_num(t as u8),
 			x if x < 256 => self.write_u8(t as u8),
@@ -309,21 +490,31 @@ impl<'a> Encoder<'a> {
 	}
 
 	/// Writes a 32-bit floating point value to the stream.
-	pub fn write_f32(&mut self, t: f32) {
-		self.write_tag(encoded_type::Float32);
-		self.writer.write_be_f32(t);
+	pub fn write_f32(&mut self, t: f32) -> Result<(), Error> {
+		let bits = match t {
+			x if self.canonical && x != x => CanonicalNan32,
+			x if self.canonical && x == 0.0 && self.negative_zero == PositiveZero => 0,
+			_ => unsafe { cast::transmute::<f32, u32>(t) }
+		};
+		try!(self.write_tag(encoded_type::Float32));
+		self.put_be32(bits)
 	}
 
 	/// Writes a 64-bit floating point value to the stream.
-	pub fn write_f64(&mut self, t: f64) {
-		self.write_tag(encoded_type::Float64);
-		self.writer.write_be_f64(t)
+	pub fn write_f64(&mut self, t: f64) -> Result<(), Error> {
+		let bits = match t {
+			x if self.canonical && x != x => CanonicalNan64,
+			x if self.canonical && x == 0.0 && self.negative_zero == PositiveZero => 0,
+			_ => unsafe { cast::transmute::<f64, u64>(t) }
+		};
+		try!(self.write_tag(encoded_type::Float64));
+		self.put_be64(bits)
 	}
 
 	/// Writes a boolean to the stream. It will be encoded on the wire as a
 	/// MsgPack `true` or a MsgPack `false` value.
 	#[inline]
-	pub fn write_bool(&mut self, t: bool) {
+	pub fn write_bool(&mut self, t: bool) -> Result<(), Error> {
 		match t {
 			true => self.write_true(),
 			false => self.write_false()
@@ -333,28 +524,17 @@ impl<'a> Encoder<'a> {
 	/// Writes a UTF-8 encoded string to the stream. The smallest possible
 	/// string header will be used to convey the length of the string on the
 	/// wire.
-	pub fn write_str(&mut self, t: &str) {
-		let x = t.len();
-		match x {
-			x if x <= 31 => self.write_str_fix(t),
-			x if x <= 255 => self.write_str8(t),
-			x if x <= 65535 => self.write_str16(t),
-			x if x <= 4294967295 => self.write_str32(t),
-			_ => { fail!(ErrWontFit) }
-		}
+	pub fn write_str(&mut self, t: &str) -> Result<(), Error> {
+		try!(self.write_str_len(t.len()));
+		self.write_raw(t.as_bytes())
 	}
 
 	/// Writes a block of binary data to the stream. The smallest possible
 	/// string header will be used to convey the length of the string on the
 	/// wire.
-	pub fn write_bin(&mut self, t: &[u8]) {
-		let x = t.len();
-		match x {
-			x if x <= 255 => self.write_bin8(t),
-			x if x <= 65535 => self.write_bin16(t),
-			x if x <= 4294967295 => self.write_bin32(t),
-			_ => { fail!(ErrWontFit) }
-		}
+	pub fn write_bin(&mut self, t: &[u8]) -> Result<(), Error> {
+		try!(self.write_bin_len(t.len()));
+		self.write_raw(t)
 	}
 
 	/// Writes an extension type to the stream. Sizes of 1, 2, 4, 8 and 16
@@ -362,48 +542,942 @@ impl<'a> Encoder<'a> {
 	/// encode binary blobs of those exact lengths. You must specify a
 	/// `type_code` to identify your application-specific extension type, and
 	/// this value must be a positive number. Negative values are reserved for
-	/// future MsgPack use, and the function will `fail` if their use is
-	/// attempted.
-	pub fn write_ext(&mut self, type_code: i8, t: &[u8]) {
-		if (type_code < 0) { fail!(ErrTypeReserved); }
-		let x = t.len();
-		match x {
-			x if x == 1 => self.write_fixext1(type_code, t[0]),
- 			x if x == 2 => self.write_fixext2(type_code, t),
- 			x if x == 4 => self.write_fixext4(type_code, t),
- 			x if x == 8 => self.write_fixext8(type_code, t),
- 			x if x == 16 => self.write_fixext16(type_code, t),
-			x if x <= 255 => self.write_ext8(type_code, t),
-			x if x <= 65535 => self.write_ext16(type_code, t),
-			x if x <= 4294967295 => self.write_ext32(type_code, t),
-			_ => { fail!(ErrWontFit) }
+	/// future MsgPack use, and the function will return a `ReservedExtension`
+	/// error if their use is attempted; see `write_reserved_ext` for writing
+	/// them on purpose.
+	pub fn write_ext(&mut self, type_code: i8, t: &[u8]) -> Result<(), Error> {
+		try!(self.write_ext_header(type_code, t.len()));
+		self.write_raw(t)
+	}
+
+	/// Writes an extension type to the stream as `write_ext` does, but
+	/// accepts any type code, including the negative codes reserved by
+	/// MsgPack. This is for writing the types the spec defines for itself,
+	/// and is up to the caller to use correctly.
+	pub fn write_reserved_ext(&mut self, type_code: i8, t: &[u8]) -> Result<(), Error> {
+		try!(self.write_reserved_ext_header(type_code, t.len()));
+		self.write_raw(t)
+	}
+
+	/// Packs `value` with the registry's handler for `type_code` and writes
+	/// it as an extension. Fails with `UnknownExtension` if there is no
+	/// registry or no handler for the type, and with `InvalidExtension` if
+	/// the handler does not accept the value.
+	pub fn write_extension(&mut self, type_code: i8, value: &Any) -> Result<(), Error> {
+		let data = match self.registry {
+			Some(ref r) => r.borrow().pack(type_code, value),
+			None => Err(UnknownExtension)
+		};
+		match data {
+			Ok(x) => self.write_reserved_ext(type_code, x),
+			Err(kind) => Err(self.error(kind))
 		}
 	}
 
 	/// Writes a vector of encodable Rust data as a MsgPack-encoded array.
-	pub fn write_array<T: Encodable>(&mut self, t: &[T]) {
-		let x = t.len();
-		match x {
-			y if y <= 16 => self.write_array8(x as u8),
-			y if y <= 65535 => self.write_array16(x as u16),
-			y if y <= 4294967295 => self.write_array32(x as u32),
-			_ => { fail!(ErrWontFit) }
-		}
+	/// The first error from an element stops the write and is returned.
+	pub fn write_array<T: Encodable>(&mut self, t: &[T]) -> Result<(), Error> {
+		try!(self.write_array_len(t.len()));
 		for z in t.iter() {
-			z.encode(self)
+			try!(z.encode(self));
 		}
+		Ok(())
 	}
 
-	// TODO map
+	/// Writes key/value pairs from an iterator as a MsgPack-encoded map.
+	/// `len` must be the number of pairs the iterator will yield, as it is
+	/// written in the header before any pair; if it is not, the write stops
+	/// with `LengthMismatch`. This is the form to use for
+	/// `HashMap`, `TreeMap` and friends, for example
+	/// `encoder.write_map_iter(map.len(), map.iter())`.
+	pub fn write_map_iter<'b, K: Encodable, V: Encodable,
+		I: Iterator<(&'b K, &'b V)>>(&mut self, len: uint, mut iter: I)
+		-> Result<(), Error>
+	{
+		if self.canonical {
+			return self.write_sorted_map(len, iter);
+		}
+		try!(self.write_map_len(len));
+		let mut count = 0;
+		for (k, v) in iter {
+			count += 1;
+			if count > len { break; }
+			try!(k.encode(self));
+			try!(v.encode(self));
+		}
+		if count != len {
+			return Err(self.error(LengthMismatch));
+		}
+		Ok(())
+	}
+
+	/// Writes a vector of key/value pairs as a MsgPack-encoded map. Pairs are
+	/// written in the order given, so duplicate keys are passed through.
+	pub fn write_map<K: Encodable, V: Encodable>(&mut self, t: &[(K, V)])
+		-> Result<(), Error>
+	{
+		self.write_map_iter(t.len(), t.iter().map(|&(ref k, ref v)| (k, v)))
+	}
+
+	/// Starts a map of `len` pairs, which are then written one at a time
+	/// with `write_entry`, and finished with `end_map`. Unlike the pairs
+	/// after `write_map_len`, these are sorted in canonical mode; this is the
+	/// form to use for structures and other maps built up a pair at a time.
+	pub fn begin_map(&mut self, len: uint) -> Result<(), Error> {
+		try!(self.write_map_len(len));
+		self.maps.push(MapFrame { len: len, count: 0, key: None, pairs: ~[] });
+		Ok(())
+	}
+
+	/// Writes one pair of the map begun by the last `begin_map`.
+	pub fn write_entry<K: Encodable, V: Encodable>(&mut self, key: &K, value: &V)
+		-> Result<(), Error>
+	{
+		self.begin_part();
+		let result = key.encode(self);
+		self.end_part(false);
+		try!(result);
+		self.begin_part();
+		let result = value.encode(self);
+		self.end_part(true);
+		result
+	}
+
+	/// Finishes the map begun by the last `begin_map`, failing with
+	/// `LengthMismatch` if it did not have as many pairs as it announced.
+	pub fn end_map(&mut self) -> Result<(), Error> {
+		let mut frame = match self.maps.pop() {
+			Some(x) => x,
+			None => return Err(self.error(LengthMismatch))
+		};
+		if frame.count != frame.len {
+			return Err(self.error(LengthMismatch));
+		}
+		frame.pairs.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+		for &(ref k, ref v) in frame.pairs.iter() {
+			try!(self.put(*k));
+			try!(self.put(*v));
+		}
+		Ok(())
+	}
 
 	/// Writes an `Option` type to the stream; if `None`, then a MsgPack NIL
 	/// is written to the stream, otherwise the encoded value of the option is
 	/// written.
 	#[experimental]
-	pub fn write_option<T: Encodable>(&mut self, t: &Option<T>) {
+	pub fn write_option<T: Encodable>(&mut self, t: &Option<T>) -> Result<(), Error> {
 		match t.as_ref() {
 			Some(x) => x.encode(self),
 			None => self.write_nil()
 		}
 	}
 }
+
+/// Methods which write only the header of a value, leaving its contents to
+/// the caller. This allows a container to be streamed out one element at a
+/// time, or a large string or binary in chunks, without building it in
+/// memory first. Each header must be followed by exactly the number of
+/// elements or bytes it announces, or the stream will not decode.
+impl<'a> Encoder<'a> {
+	/// Writes the header of an array which holds `len` elements. The elements
+	/// are written afterwards with any of the other `write` methods.
+	pub fn write_array_len(&mut self, len: uint) -> Result<(), Error> {
+		try!(match len {
+			y if y <= 15 => self.write_array8(len as u8),
+			y if y <= 65535 => self.write_array16(len as u16),
+			y if y <= 4294967295 => self.write_array32(len as u32),
+			_ => Err(self.error(LengthOverflow))
+		});
+		self.opened(len, false);
+		Ok(())
+	}
+
+	/// Writes the header of a map which holds `len` key/value pairs. The
+	/// pairs are written afterwards, each key followed by its value.
+	pub fn write_map_len(&mut self, len: uint) -> Result<(), Error> {
+		try!(match len {
+			y if y <= 15 => self.write_map_fix(len as u8),
+			y if y <= 65535 => self.write_map16(len as u16),
+			y if y <= 4294967295 => self.write_map32(len as u32),
+			_ => Err(self.error(LengthOverflow))
+		});
+		self.opened(len, true);
+		Ok(())
+	}
+
+	/// Writes the header of a string of `len` bytes. The UTF-8 data is
+	/// written afterwards with `write_raw`.
+	pub fn write_str_len(&mut self, len: uint) -> Result<(), Error> {
+		match len {
+			x if x <= 31 => self.write_str_fix(len as u8),
+			x if x <= 255 && !self.compatibility => self.write_str8(len as u8),
+			x if x <= 65535 => self.write_str16(len as u16),
+			x if x <= 4294967295 => self.write_str32(len as u32),
+			_ => Err(self.error(LengthOverflow))
+		}
+	}
+
+	/// Writes the header of a binary of `len` bytes. The data is written
+	/// afterwards with `write_raw`. In compatibility mode this writes a
+	/// string header instead.
+	pub fn write_bin_len(&mut self, len: uint) -> Result<(), Error> {
+		if self.profile.is_some() {
+			return Err(self.error(ProfileViolation));
+		}
+		if self.compatibility {
+			return self.write_str_len(len);
+		}
+		match len {
+			x if x <= 255 => self.write_bin8(len as u8),
+			x if x <= 65535 => self.write_bin16(len as u16),
+			x if x <= 4294967295 => self.write_bin32(len as u32),
+			_ => Err(self.error(LengthOverflow))
+		}
+	}
+
+	/// Writes the header of an extension of `len` bytes, using a fixext
+	/// header where one fits. The payload is written afterwards with
+	/// `write_raw`. As with `write_ext`, negative type codes are refused.
+	pub fn write_ext_header(&mut self, type_code: i8, len: uint) -> Result<(), Error> {
+		if (type_code < 0) { return Err(self.error(ReservedExtension)); }
+		self.write_reserved_ext_header(type_code, len)
+	}
+
+	/// Writes the header of an extension as `write_ext_header` does, but
+	/// accepts any type code; see `write_reserved_ext`.
+	pub fn write_reserved_ext_header(&mut self, type_code: i8, len: uint) -> Result<(), Error> {
+		match len {
+			x if x == 1 => self.write_fixext(encoded_type::Fixext1, type_code),
+			x if x == 2 => self.write_fixext(encoded_type::Fixext2, type_code),
+			x if x == 4 => self.write_fixext(encoded_type::Fixext4, type_code),
+			x if x == 8 => self.write_fixext(encoded_type::Fixext8, type_code),
+			x if x == 16 => self.write_fixext(encoded_type::Fixext16, type_code),
+			x if x <= 255 => self.write_ext8(type_code, len as u8),
+			x if x <= 65535 => self.write_ext16(type_code, len as u16),
+			x if x <= 4294967295 => self.write_ext32(type_code, len as u32),
+			_ => Err(self.error(LengthOverflow))
+		}
+	}
+
+	/// Writes bytes to the stream exactly as given, with no header. This is
+	/// for the contents of strings, binaries and extensions whose header was
+	/// written separately.
+	pub fn write_raw(&mut self, data: &[u8]) -> Result<(), Error> {
+		self.put(data)
+	}
+}
+
+/* Implementations for the standard library types. Integers always take the
+ * smallest encoding, whatever their width in Rust. */
+
+macro_rules! encodable_uint(
+	($t:ty) => (
+		impl Encodable for $t {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				destination.write_uint(*self as u64)
+			}
+		}
+	)
+)
+
+macro_rules! encodable_int(
+	($t:ty) => (
+		impl Encodable for $t {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				destination.write_int(*self as i64)
+			}
+		}
+	)
+)
+
+encodable_uint!(u8)
+encodable_uint!(u16)
+encodable_uint!(u32)
+encodable_uint!(u64)
+encodable_uint!(uint)
+encodable_int!(i8)
+encodable_int!(i16)
+encodable_int!(i32)
+encodable_int!(i64)
+encodable_int!(int)
+
+impl Encodable for f32 {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_f32(*self)
+	}
+}
+
+impl Encodable for f64 {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_f64(*self)
+	}
+}
+
+impl Encodable for bool {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_bool(*self)
+	}
+}
+
+/* A unit carries no data, so it becomes a nil. */
+impl Encodable for () {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_nil()
+	}
+}
+
+/* A char is written as a string holding just that character. */
+impl Encodable for char {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_str(str::from_char(*self))
+	}
+}
+
+impl<'a> Encodable for &'a str {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_str(*self)
+	}
+}
+
+impl Encodable for ~str {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_str(*self)
+	}
+}
+
+/* Vectors of any type, bytes included, become arrays; use `write_bin` to
+ * write bytes as a binary. */
+impl<'a, T: Encodable> Encodable for &'a [T] {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_array(*self)
+	}
+}
+
+impl<T: Encodable> Encodable for ~[T] {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_array(*self)
+	}
+}
+
+impl<T: Encodable> Encodable for Option<T> {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_option(self)
+	}
+}
+
+impl<'a, T: Encodable> Encodable for &'a T {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		(**self).encode(destination)
+	}
+}
+
+impl<T: Encodable> Encodable for ~T {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		(**self).encode(destination)
+	}
+}
+
+impl<T: Encodable> Encodable for Rc<T> {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		self.borrow().encode(destination)
+	}
+}
+
+/* Decoded values are written back in their smallest encoding. Extensions
+ * keep their type code, reserved or not, so that a decoded message can be
+ * passed on intact. */
+impl Encodable for Value {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		match *self {
+			Signed(x) => destination.write_int(x),
+			Unsigned(x) => destination.write_uint(x),
+			String(ref x) => destination.write_str(*x),
+			Binary(ref x) => destination.write_bin(*x),
+			Array(ref x) => destination.write_array(*x),
+			Float32(x) => destination.write_f32(x),
+			Float64(x) => destination.write_f64(x),
+			Extension(type_code, ref x) => {
+				try!(destination.write_reserved_ext_header(type_code, x.len()));
+				destination.write_raw(*x)
+			}
+			Time(ref x) => x.encode(destination),
+			Custom(ref x) => destination.write_reserved_ext(x.type_code(), x.raw()),
+			Map(ref x) => destination.write_map(*x),
+			Boolean(x) => destination.write_bool(x),
+			Nil => destination.write_nil()
+		}
+	}
+}
+
+/* Lossless values are written with the tag they were read with. Contents
+ * which no longer fit that tag give `LengthOverflow` or `OutOfRange`, and
+ * a tag from the wrong family gives `TypeMismatch`. */
+impl Encodable for lossless::Value {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		let tag = self.tag;
+		match self.data {
+			lossless::Scalar(ref x) => destination.write_scalar_as(tag, x),
+			lossless::Array(ref items) => {
+				try!(destination.write_array_as(tag, items.len()));
+				for x in items.iter() {
+					try!(x.encode(destination));
+				}
+				Ok(())
+			}
+			lossless::Map(ref pairs) => {
+				try!(destination.write_map_as(tag, pairs.len()));
+				for &(ref k, ref v) in pairs.iter() {
+					try!(k.encode(destination));
+					try!(v.encode(destination));
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+/* Tuples become arrays of their fields. */
+macro_rules! encodable_tuple(
+	($len:expr, $($name:ident),+) => (
+		impl<$($name: Encodable),+> Encodable for ($($name,)+) {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				let ($(ref $name,)+) = *self;
+				try!(destination.write_array_len($len));
+				$(try!($name.encode(destination));)+
+				Ok(())
+			}
+		}
+	)
+)
+
+encodable_tuple!(1, A)
+encodable_tuple!(2, A, B)
+encodable_tuple!(3, A, B, C)
+encodable_tuple!(4, A, B, C, D)
+encodable_tuple!(5, A, B, C, D, E)
+encodable_tuple!(6, A, B, C, D, E, F)
+encodable_tuple!(7, A, B, C, D, E, F, G)
+encodable_tuple!(8, A, B, C, D, E, F, G, H)
+
+macro_rules! encodable_fixed_vec(
+	($($len:expr),+) => (
+		$(
+			impl<T: Encodable> Encodable for [T, ..$len] {
+				fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+					destination.write_array(self.as_slice())
+				}
+			}
+		)+
+	)
+)
+
+encodable_fixed_vec!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+	17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32)
+
+impl<K: Encodable + Hash + Eq, V: Encodable> Encodable for HashMap<K, V> {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_map_iter(self.len(), self.iter())
+	}
+}
+
+impl<K: Encodable + TotalOrd, V: Encodable> Encodable for TreeMap<K, V> {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_map_iter(self.len(), self.iter())
+	}
+}
+
+/* Sets become arrays of their members. */
+impl<T: Encodable + Hash + Eq> Encodable for HashSet<T> {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_set(self.len(), self.iter())
+	}
+}
+
+impl<T: Encodable + TotalOrd> Encodable for TreeSet<T> {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		destination.write_set(self.len(), self.iter())
+	}
+}
+
+/* Appends to a byte vector the caller already has, for encode_into. */
+struct VecWriter<'a> {
+	buffer: &'a mut ~[u8]
+}
+
+impl<'a> VecWriter<'a> {
+	fn new(buffer: &'a mut ~[u8]) -> VecWriter<'a> {
+		VecWriter { buffer: buffer }
+	}
+}
+
+impl<'a> io::Writer for VecWriter<'a> {
+	fn write(&mut self, buf: &[u8]) {
+		self.buffer.push_all(buf);
+	}
+}
+
+/// Encodes `t` in to a new byte vector, with an encoder's default settings.
+pub fn to_bytes<T: Encodable>(t: &T) -> Result<~[u8], Error> {
+	let mut writer = MemWriter::new();
+	/* encoding cycle */ {
+		let mut encoder = Encoder::new(&mut writer as &mut io::Writer);
+		try!(encoder.write(t));
+	}
+	Ok(writer.inner())
+}
+
+/// Encodes `t` on to the end of `buffer`, with an encoder's default
+/// settings. If encoding fails, `buffer` is left as it was, and the offset
+/// in the error counts from the start of `t`.
+pub fn encode_into<T: Encodable>(t: &T, buffer: &mut ~[u8]) -> Result<(), Error> {
+	let start = buffer.len();
+	let result = {
+		let mut writer = VecWriter::new(buffer);
+		let mut encoder = Encoder::new(&mut writer as &mut io::Writer);
+		encoder.write(t)
+	};
+	if result.is_err() {
+		buffer.truncate(start);
+	}
+	result
+}
+
+fn int_between(value: &Value, min: i64, max: i64) -> bool {
+	match *value {
+		Signed(x) => x >= min && x <= max,
+		Unsigned(x) => max >= 0 && x <= max as u64,
+		_ => false
+	}
+}
+
+/* Canonical mode writes the parts of a map or set apart first, so that they
+ * can be sorted by their encoded bytes. */
+impl<'a> Encoder<'a> {
+	/* Writes a value which was encoded apart, so that the profile still
+	 * sees where it starts. */
+	fn write_encoded(&mut self, data: &[u8]) -> Result<(), Error> {
+		self.tag = Some(data[0]);
+		try!(self.check_profile(data[0]));
+		self.put(data)
+	}
+
+	/* Starts a key or value of the map begun by the last `begin_map`; in
+	 * canonical mode, it is held back to be sorted with the others. */
+	fn begin_part(&mut self) {
+		if self.canonical {
+			self.captures.push(~[]);
+		}
+	}
+
+	/* Ends a key or value started with `begin_part`. A pair is counted
+	 * once its value is done. */
+	fn end_part(&mut self, value: bool) {
+		let data = if self.canonical { self.captures.pop() } else { None };
+		let top = self.maps.len();
+		if top == 0 {
+			return;
+		}
+		let frame = &mut self.maps[top - 1];
+		if value {
+			frame.count += 1;
+		}
+		match data {
+			Some(x) => if value {
+				let key = frame.key.take().unwrap_or(~[]);
+				frame.pairs.push((key, x));
+			} else {
+				frame.key = Some(x);
+			},
+			None => {}
+		}
+	}
+
+	fn encode_apart<T: Encodable>(&self, t: &T) -> Result<~[u8], Error> {
+		let mut writer = MemWriter::new();
+		/* encoding cycle */ {
+			let mut child = self.child(&mut writer as &mut io::Writer);
+			try!(child.write(t));
+		}
+		Ok(writer.inner())
+	}
+
+	fn write_sorted_map<'b, K: Encodable, V: Encodable,
+		I: Iterator<(&'b K, &'b V)>>(&mut self, len: uint, mut iter: I) -> Result<(), Error>
+	{
+		let mut pairs : ~[(~[u8], ~[u8])] = ~[];
+		for (k, v) in iter {
+			pairs.push((try!(self.encode_apart(k)), try!(self.encode_apart(v))));
+		}
+		if pairs.len() != len {
+			return Err(self.error(LengthMismatch));
+		}
+		pairs.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+		try!(self.write_map_len(pairs.len()));
+		for &(ref k, ref v) in pairs.iter() {
+			try!(self.write_encoded(*k));
+			try!(self.write_encoded(*v));
+		}
+		Ok(())
+	}
+
+	/* Sets are written as arrays; in canonical mode their members are
+	 * sorted, as a set has no order of its own. */
+	fn write_set<'b, T: Encodable, I: Iterator<&'b T>>(&mut self, len: uint, mut iter: I)
+		-> Result<(), Error>
+	{
+		if !self.canonical {
+			try!(self.write_array_len(len));
+			for x in iter {
+				try!(x.encode(self));
+			}
+			return Ok(());
+		}
+		let mut members : ~[~[u8]] = ~[];
+		for x in iter {
+			members.push(try!(self.encode_apart(x)));
+		}
+		members.sort();
+		try!(self.write_array_len(members.len()));
+		for x in members.iter() {
+			try!(self.write_encoded(*x));
+		}
+		Ok(())
+	}
+}
+
+/* Writers which use a given tag rather than the smallest one, for
+ * `lossless::Value`. */
+impl<'a> Encoder<'a> {
+	fn fits(&self, len: uint, max: uint) -> Result<(), Error> {
+		if len > max {
+			Err(self.error(LengthOverflow))
+		} else {
+			Ok(())
+		}
+	}
+
+	fn write_array_as(&mut self, tag: u8, len: uint) -> Result<(), Error> {
+		try!(match tag {
+			x if ((x & 0xF0) == 0x90) => {
+				try!(self.fits(len, 15));
+				self.write_array8(len as u8)
+			}
+			encoded_type::Array16 => {
+				try!(self.fits(len, 65535));
+				self.write_array16(len as u16)
+			}
+			encoded_type::Array32 => {
+				try!(self.fits(len, 4294967295));
+				self.write_array32(len as u32)
+			}
+			_ => Err(self.error(TypeMismatch))
+		});
+		self.opened(len, false);
+		Ok(())
+	}
+
+	fn write_map_as(&mut self, tag: u8, len: uint) -> Result<(), Error> {
+		try!(match tag {
+			x if ((x & 0xF0) == 0x80) => {
+				try!(self.fits(len, 15));
+				self.write_map_fix(len as u8)
+			}
+			encoded_type::Map16 => {
+				try!(self.fits(len, 65535));
+				self.write_map16(len as u16)
+			}
+			encoded_type::Map32 => {
+				try!(self.fits(len, 4294967295));
+				self.write_map32(len as u32)
+			}
+			_ => Err(self.error(TypeMismatch))
+		});
+		self.opened(len, true);
+		Ok(())
+	}
+
+	fn write_scalar_as(&mut self, tag: u8, value: &Value) -> Result<(), Error> {
+		match *value {
+			Nil if tag == encoded_type::Nil => self.write_nil(),
+			Boolean(x) if tag == encoded_type::True || tag == encoded_type::False => self.write_bool(x),
+			Float32(x) if tag == encoded_type::Float32 => self.write_f32(x),
+			Float64(x) if tag == encoded_type::Float64 => self.write_f64(x),
+			Signed(_) | Unsigned(_) => self.write_integer_as(tag, value),
+			String(ref x) => self.write_bytes_as(tag, x.as_bytes()),
+			Binary(ref x) => self.write_bytes_as(tag, *x),
+			Extension(type_code, ref x) => self.write_ext_as(tag, type_code, *x),
+			Time(ref x) => match x.to_bytes() {
+				Some(data) => self.write_ext_as(tag, timestamp::TypeCode, data),
+				None => Err(self.error(InvalidTimestamp))
+			},
+			Custom(ref x) => self.write_ext_as(tag, x.type_code(), x.raw()),
+			_ => Err(self.error(TypeMismatch))
+		}
+	}
+
+	fn write_integer_as(&mut self, tag: u8, value: &Value) -> Result<(), Error> {
+		let fits = match tag {
+			x if ((x & 0x80) == 0) => int_between(value, 0, 127),
+			x if ((x & 0xE0) == 0xE0) => int_between(value, -32, -1),
+			encoded_type::Uint8 => int_between(value, 0, 255),
+			encoded_type::Uint16 => int_between(value, 0, 65535),
+			encoded_type::Uint32 => int_between(value, 0, 4294967295),
+			encoded_type::Uint64 => match *value {
+				Signed(x) => x >= 0,
+				_ => true
+			},
+			encoded_type::Int8 => int_between(value, -128, 127),
+			encoded_type::Int16 => int_between(value, -32768, 32767),
+			encoded_type::Int32 => int_between(value, -2147483648, 2147483647),
+			encoded_type::Int64 => int_between(value, i64::min_value, i64::max_value),
+			_ => return Err(self.error(TypeMismatch))
+		};
+		if !fits {
+			return Err(self.error(OutOfRange));
+		}
+		let bits = match *value {
+			Signed(x) => x as u64,
+			Unsigned(x) => x,
+			_ => unreachable!()
+		};
+		match tag {
+			x if ((x & 0x80) == 0) => self.write_fix_num(bits as u8),
+			x if ((x & 0xE0) == 0xE0) => self.write_fix_neg(bits as i8),
+			encoded_type::Uint8 => self.write_u8(bits as u8),
+			encoded_type::Uint16 => self.write_u16(bits as u16),
+			encoded_type::Uint32 => self.write_u32(bits as u32),
+			encoded_type::Uint64 => self.write_u64(bits),
+			encoded_type::Int8 => self.write_i8(bits as i8),
+			encoded_type::Int16 => self.write_i16(bits as i16),
+			encoded_type::Int32 => self.write_i32(bits as i32),
+			_ => self.write_i64(bits as i64)
+		}
+	}
+
+	/* Strings and binaries are both just bytes once written, so either may
+	 * be written with the tag of the other. */
+	fn write_bytes_as(&mut self, tag: u8, data: &[u8]) -> Result<(), Error> {
+		let len = data.len();
+		try!(match tag {
+			x if ((x & 0xE0) == 0xA0) => {
+				try!(self.fits(len, 31));
+				self.write_str_fix(len as u8)
+			}
+			encoded_type::Str8 => {
+				try!(self.fits(len, 255));
+				self.write_str8(len as u8)
+			}
+			encoded_type::Str16 => {
+				try!(self.fits(len, 65535));
+				self.write_str16(len as u16)
+			}
+			encoded_type::Str32 => {
+				try!(self.fits(len, 4294967295));
+				self.write_str32(len as u32)
+			}
+			encoded_type::Bin8 => {
+				try!(self.fits(len, 255));
+				self.write_bin8(len as u8)
+			}
+			encoded_type::Bin16 => {
+				try!(self.fits(len, 65535));
+				self.write_bin16(len as u16)
+			}
+			encoded_type::Bin32 => {
+				try!(self.fits(len, 4294967295));
+				self.write_bin32(len as u32)
+			}
+			_ => Err(self.error(TypeMismatch))
+		});
+		self.write_raw(data)
+	}
+
+	fn write_ext_as(&mut self, tag: u8, type_code: i8, data: &[u8]) -> Result<(), Error> {
+		let len = data.len();
+		let fixed = match tag {
+			encoded_type::Fixext1 => 1,
+			encoded_type::Fixext2 => 2,
+			encoded_type::Fixext4 => 4,
+			encoded_type::Fixext8 => 8,
+			encoded_type::Fixext16 => 16,
+			_ => 0
+		};
+		try!(match tag {
+			_ if fixed > 0 => {
+				if len != fixed {
+					return Err(self.error(LengthOverflow));
+				}
+				self.write_fixext(tag, type_code)
+			}
+			encoded_type::Ext8 => {
+				try!(self.fits(len, 255));
+				self.write_ext8(type_code, len as u8)
+			}
+			encoded_type::Ext16 => {
+				try!(self.fits(len, 65535));
+				self.write_ext16(type_code, len as u16)
+			}
+			encoded_type::Ext32 => {
+				try!(self.fits(len, 4294967295));
+				self.write_ext32(type_code, len as u32)
+			}
+			_ => Err(self.error(TypeMismatch))
+		});
+		self.write_raw(data)
+	}
+}
+
+impl<'a> Encoder<'a> {
+	/// Writes the start of an enum variant with `len` arguments, which are
+	/// to be written after it. A variant without arguments is just its
+	/// name; otherwise it is a single-pair map from the name to an array of
+	/// the arguments.
+	pub fn write_variant(&mut self, name: &str, len: uint) -> Result<(), Error> {
+		if len > 0 {
+			try!(self.write_map_len(1));
+			try!(self.write_str(name));
+			self.write_array_len(len)
+		} else {
+			self.write_str(name)
+		}
+	}
+}
+
+/* The standard serialization traits have no way to report an error, so a
+ * failed write fails the task instead. Use `Encodable` where that matters. */
+fn unwrap_write(result: Result<(), Error>) {
+	match result {
+		Ok(()) => {},
+		Err(e) => fail!(e.to_str())
+	}
+}
+
+/// Lets any type deriving `extra::serialize::Encodable` be written as
+/// MsgPack. Structures become maps keyed by field name, tuples, tuple
+/// structures and sequences become arrays, and `None` becomes nil. An enum
+/// variant without arguments becomes its name as a string; one with
+/// arguments becomes a single-pair map from its name to an array of them.
+/// In canonical mode, the fields of a structure and the pairs of a map are
+/// sorted as `begin_map` sorts them. Write errors fail the task.
+impl<'a> serialize::Encoder for Encoder<'a> {
+	fn emit_nil(&mut self) { unwrap_write(self.write_nil()) }
+
+	fn emit_uint(&mut self, v: uint) { unwrap_write(self.write_uint(v as u64)) }
+	fn emit_u64(&mut self, v: u64) { unwrap_write(self.write_uint(v)) }
+	fn emit_u32(&mut self, v: u32) { unwrap_write(self.write_uint(v as u64)) }
+	fn emit_u16(&mut self, v: u16) { unwrap_write(self.write_uint(v as u64)) }
+	fn emit_u8(&mut self, v: u8) { unwrap_write(self.write_uint(v as u64)) }
+
+	fn emit_int(&mut self, v: int) { unwrap_write(self.write_int(v as i64)) }
+	fn emit_i64(&mut self, v: i64) { unwrap_write(self.write_int(v)) }
+	fn emit_i32(&mut self, v: i32) { unwrap_write(self.write_int(v as i64)) }
+	fn emit_i16(&mut self, v: i16) { unwrap_write(self.write_int(v as i64)) }
+	fn emit_i8(&mut self, v: i8) { unwrap_write(self.write_int(v as i64)) }
+
+	fn emit_bool(&mut self, v: bool) { unwrap_write(self.write_bool(v)) }
+	fn emit_f64(&mut self, v: f64) { unwrap_write(self.write_f64(v)) }
+	fn emit_f32(&mut self, v: f32) { unwrap_write(self.write_f32(v)) }
+	fn emit_char(&mut self, v: char) { unwrap_write(self.write_str(str::from_char(v))) }
+	fn emit_str(&mut self, v: &str) { unwrap_write(self.write_str(v)) }
+
+	fn emit_enum(&mut self, _name: &str, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_enum_variant(&mut self, v_name: &str, _v_id: uint, len: uint,
+		f: |&mut Encoder<'a>|)
+	{
+		unwrap_write(self.write_variant(v_name, len));
+		f(self)
+	}
+
+	fn emit_enum_variant_arg(&mut self, _a_idx: uint, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_enum_struct_variant(&mut self, v_name: &str, _v_id: uint, len: uint,
+		f: |&mut Encoder<'a>|)
+	{
+		unwrap_write(self.write_variant(v_name, len));
+		f(self)
+	}
+
+	fn emit_enum_struct_variant_field(&mut self, _f_name: &str, _f_idx: uint,
+		f: |&mut Encoder<'a>|)
+	{
+		f(self)
+	}
+
+	fn emit_struct(&mut self, _name: &str, len: uint, f: |&mut Encoder<'a>|) {
+		unwrap_write(self.begin_map(len));
+		f(self);
+		unwrap_write(self.end_map())
+	}
+
+	fn emit_struct_field(&mut self, f_name: &str, _f_idx: uint, f: |&mut Encoder<'a>|) {
+		self.begin_part();
+		let result = self.write_str(f_name);
+		self.end_part(false);
+		unwrap_write(result);
+		self.begin_part();
+		f(self);
+		self.end_part(true)
+	}
+
+	fn emit_tuple(&mut self, len: uint, f: |&mut Encoder<'a>|) {
+		unwrap_write(self.write_array_len(len));
+		f(self)
+	}
+
+	fn emit_tuple_arg(&mut self, _idx: uint, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_tuple_struct(&mut self, _name: &str, len: uint, f: |&mut Encoder<'a>|) {
+		unwrap_write(self.write_array_len(len));
+		f(self)
+	}
+
+	fn emit_tuple_struct_arg(&mut self, _f_idx: uint, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_option(&mut self, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_option_none(&mut self) {
+		unwrap_write(self.write_nil())
+	}
+
+	fn emit_option_some(&mut self, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_seq(&mut self, len: uint, f: |&mut Encoder<'a>|) {
+		unwrap_write(self.write_array_len(len));
+		f(self)
+	}
+
+	fn emit_seq_elt(&mut self, _idx: uint, f: |&mut Encoder<'a>|) {
+		f(self)
+	}
+
+	fn emit_map(&mut self, len: uint, f: |&mut Encoder<'a>|) {
+		unwrap_write(self.begin_map(len));
+		f(self);
+		unwrap_write(self.end_map())
+	}
+
+	fn emit_map_elt_key(&mut self, _idx: uint, f: |&mut Encoder<'a>|) {
+		self.begin_part();
+		f(self);
+		self.end_part(false)
+	}
+
+	fn emit_map_elt_val(&mut self, _idx: uint, f: |&mut Encoder<'a>|) {
+		self.begin_part();
+		f(self);
+		self.end_part(true)
+	}
+}
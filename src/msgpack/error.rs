@@ -0,0 +1,128 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use std::io;
+
+/// Describes what went wrong.
+pub enum ErrorKind {
+	/// The underlying reader or writer raised an I/O error.
+	IoFailure(io::IoError),
+	/// The stream ended in the middle of a value.
+	UnexpectedEof,
+	/// A string value did not contain valid UTF-8.
+	InvalidUtf8,
+	/// The tag byte 0xC1, which MsgPack never uses, was encountered.
+	ReservedTag,
+	/// An extension type code in the reserved (negative) range was used.
+	ReservedExtension,
+	/// A length does not fit in the header MsgPack provides for it.
+	LengthOverflow,
+	/// Containers were nested deeper than allowed.
+	DepthLimitExceeded,
+	/// A value was larger than allowed.
+	SizeLimitExceeded,
+	/// The value found is not of the type that was asked for.
+	TypeMismatch,
+	/// An integer does not fit in the type that was asked for.
+	OutOfRange,
+	/// A value is not allowed by the JSON profile.
+	ProfileViolation,
+	/// A timestamp extension has the wrong length, or a timestamp has more
+	/// than a second's worth of nanoseconds.
+	InvalidTimestamp,
+	/// No handler is registered for an extension type code.
+	UnknownExtension,
+	/// An extension handler could not convert a value or payload.
+	InvalidExtension,
+	/// Bytes were left over after the value.
+	TrailingData,
+	/// A container held a different number of elements than its header
+	/// gave.
+	LengthMismatch
+}
+
+impl ErrorKind {
+	/// A short, human readable description of the error.
+	pub fn description(&self) -> &'static str {
+		match *self {
+			IoFailure(ref e) => e.desc,
+			UnexpectedEof => "Not enough bytes available to read value.",
+			InvalidUtf8 => "String data is not valid UTF-8.",
+			ReservedTag => "Tag 0xC1 is never used by MsgPack.",
+			ReservedExtension => "Negative types are reserved for msgpack.",
+			LengthOverflow => "Provided type is too large to be encoded in msgpack.",
+			DepthLimitExceeded => "Values are nested too deeply.",
+			SizeLimitExceeded => "Value is larger than allowed.",
+			TypeMismatch => "Value is not of the expected type.",
+			OutOfRange => "Integer is out of range for the expected type.",
+			ProfileViolation => "Value is not allowed by the JSON profile.",
+			InvalidTimestamp => "Timestamp is malformed.",
+			UnknownExtension => "No handler is registered for the extension type.",
+			InvalidExtension => "Extension handler could not convert the value.",
+			TrailingData => "Data continues after the end of the value.",
+			LengthMismatch => "Container length does not match its contents."
+		}
+	}
+}
+
+/// An error, along with where in the stream it happened.
+pub struct Error {
+	/// What went wrong.
+	kind: ErrorKind,
+	/// When decoding, where in the stream the value being read starts; for
+	/// truncated input, where the data ran out, and for invalid UTF-8, the
+	/// first byte which is not part of a valid sequence. When encoding, the
+	/// number of bytes written before the error was found.
+	offset: u64,
+	/// The tag byte of the value being processed, if one was seen.
+	tag: Option<u8>
+}
+
+impl Error {
+	pub fn new(kind: ErrorKind, offset: u64, tag: Option<u8>) -> Error {
+		Error { kind: kind, offset: offset, tag: tag }
+	}
+}
+
+impl ToStr for Error {
+	fn to_str(&self) -> ~str {
+		match self.tag {
+			Some(t) => format!("{} (tag 0x{:x}, offset {})",
+				self.kind.description(), t, self.offset),
+			None => format!("{} (offset {})",
+				self.kind.description(), self.offset)
+		}
+	}
+}
+
+/// Runs `f`, turning any `io_error` condition it raises in to an `Err`
+/// instead of failing the task.
+#[doc(hidden)]
+pub fn trap_io<T>(f: || -> T) -> Result<T, io::IoError> {
+	let mut failure = None;
+	let result = io::io_error::cond.trap(|e| failure = Some(e)).inside(f);
+	match failure {
+		Some(e) => Err(e),
+		None => Ok(result)
+	}
+}
@@ -0,0 +1,195 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use error::*;
+
+use std::any::{Any, AnyOwnExt, AnyRefExt};
+use std::hashmap::HashMap;
+
+/// Turns an application value in to the payload of its extension, or
+/// returns `None` if the value is not of the type the handler expects.
+pub type PackFn = fn(&Any) -> Option<~[u8]>;
+
+/// Turns the payload of an extension back in to an application value, or
+/// returns `None` if the payload is malformed.
+pub type UnpackFn = fn(&[u8]) -> Option<~Any>;
+
+/// What a `Decoder` with a registry does with an extension whose type code
+/// has no handler.
+#[deriving(Clone, Eq)]
+pub enum UnknownPolicy {
+	/// Return it as a plain `Extension`.
+	KeepRaw,
+	/// Fail with `UnknownExtension`.
+	RejectUnknown
+}
+
+struct Handler {
+	pack: PackFn,
+	unpack: UnpackFn
+}
+
+/// Maps extension type codes to the application types they carry. Attach
+/// one to an `Encoder` or `Decoder` with `set_registry`, and registered
+/// types are then read as `Custom` values rather than raw bytes. Handlers
+/// take precedence over the types MsgPack defines itself, such as the
+/// timestamp.
+pub struct Registry {
+	priv handlers: HashMap<i8, Handler>,
+	priv policy: UnknownPolicy
+}
+
+impl Registry {
+	pub fn new(policy: UnknownPolicy) -> Registry {
+		Registry { handlers: HashMap::new(), policy: policy }
+	}
+
+	/// Registers the handler for `type_code`, replacing any earlier one.
+	pub fn register(&mut self, type_code: i8, pack: PackFn, unpack: UnpackFn) {
+		self.handlers.insert(type_code, Handler { pack: pack, unpack: unpack });
+	}
+
+	pub fn policy(&self) -> UnknownPolicy {
+		self.policy
+	}
+
+	/// Returns true if there is a handler for `type_code`.
+	pub fn handles(&self, type_code: i8) -> bool {
+		self.handlers.contains_key(&type_code)
+	}
+
+	/// Packs `value` with the handler for `type_code`.
+	pub fn pack(&self, type_code: i8, value: &Any) -> Result<~[u8], ErrorKind> {
+		match self.handlers.find(&type_code) {
+			Some(handler) => match (handler.pack)(value) {
+				Some(data) => Ok(data),
+				None => Err(InvalidExtension)
+			},
+			None => Err(UnknownExtension)
+		}
+	}
+
+	/// Unpacks `data` with the handler for `type_code`.
+	pub fn unpack(&self, type_code: i8, data: ~[u8]) -> Result<ExtensionObject, ErrorKind> {
+		match self.handlers.find(&type_code) {
+			Some(handler) => match (handler.unpack)(data) {
+				Some(value) => Ok(ExtensionObject {
+					type_code: type_code,
+					value: Some(value),
+					unpack: handler.unpack,
+					raw: data
+				}),
+				None => Err(InvalidExtension)
+			},
+			None => Err(UnknownExtension)
+		}
+	}
+
+	/// Packs `value` with the handler for `type_code`, giving an object
+	/// which can be put in a `Value` and written like a decoded one.
+	pub fn wrap(&self, type_code: i8, value: ~Any) -> Result<ExtensionObject, ErrorKind> {
+		let data = try!(self.pack(type_code, &*value));
+		let unpack = self.handlers.get(&type_code).unpack;
+		Ok(ExtensionObject { type_code: type_code, value: Some(value), unpack: unpack, raw: data })
+	}
+}
+
+/// An extension which a registry handler turned in to an application value,
+/// along with the payload it came from. Objects are `Send`, so a `Value`
+/// holding one may be passed between tasks.
+pub struct ExtensionObject {
+	priv type_code: i8,
+	/* `None` in a clone until the value is asked for */
+	priv value: Option<~Any>,
+	/* the handler which made `value`, for unpacking a clone */
+	priv unpack: UnpackFn,
+	priv raw: ~[u8]
+}
+
+impl ExtensionObject {
+	pub fn type_code(&self) -> i8 {
+		self.type_code
+	}
+
+	/// Returns the application value, if it is a `T`. A clone unpacks its
+	/// payload again the first time it is asked, and gives `None` if the
+	/// handler refuses it then.
+	pub fn get<'a, T: 'static>(&'a mut self) -> Option<&'a T> {
+		self.unpacked();
+		match self.value {
+			Some(ref value) => {
+				let value : &'a Any = &**value;
+				value.as_ref::<T>()
+			}
+			None => None
+		}
+	}
+
+	/// Moves the application value out, if it is a `T`; otherwise gives the
+	/// object back.
+	pub fn take<T: 'static>(mut self) -> Result<T, ExtensionObject> {
+		self.unpacked();
+		match self.value.take() {
+			Some(value) => match value.move::<T>() {
+				Ok(x) => Ok(*x),
+				Err(value) => {
+					self.value = Some(value);
+					Err(self)
+				}
+			},
+			None => Err(self)
+		}
+	}
+
+	/// The payload of the extension; writing the object writes this.
+	pub fn raw<'a>(&'a self) -> &'a [u8] {
+		self.raw.as_slice()
+	}
+
+	fn unpacked(&mut self) {
+		if self.value.is_none() {
+			let value = (self.unpack)(self.raw);
+			self.value = value;
+		}
+	}
+}
+
+/* Objects compare by their payload, as the values themselves may not. */
+impl Eq for ExtensionObject {
+	fn eq(&self, other: &ExtensionObject) -> bool {
+		self.type_code == other.type_code && self.raw == other.raw
+	}
+}
+
+/* An `Any` cannot be copied, so a clone holds just the payload, and unpacks
+ * it when the value is first asked for. */
+impl Clone for ExtensionObject {
+	fn clone(&self) -> ExtensionObject {
+		ExtensionObject {
+			type_code: self.type_code,
+			value: None,
+			unpack: self.unpack,
+			raw: self.raw.clone()
+		}
+	}
+}
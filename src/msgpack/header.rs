@@ -0,0 +1,120 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use error::*;
+use magic::*;
+
+use std::cast;
+
+/* The fixed-size part of a value: what its tag and the length or number
+ * which follows the tag say. Both decoders read headers through `read` and
+ * only differ in what they do with the body. */
+pub enum Header {
+	Unsigned(u64),
+	Signed(i64),
+	Float32(f32),
+	Float64(f64),
+	Boolean(bool),
+	Nil,
+	/* lengths of the body which follows, in bytes or elements; a map's
+	 * length counts pairs, and an extension's leaves out its type code */
+	Str(uint),
+	Bin(uint),
+	Ext(uint),
+	Array(uint),
+	Map(uint),
+	/* 0xC1, which MsgPack never uses */
+	Reserved
+}
+
+/* Where a decoder gets the bytes which follow a tag. */
+pub trait Source {
+	/* Reads `width` bytes, at most 8, as a big-endian number. */
+	fn read_be(&mut self, width: uint) -> Result<u64, Error>;
+}
+
+pub fn from_be(data: &[u8]) -> u64 {
+	let mut x : u64 = 0;
+	for b in data.iter() {
+		x = (x << 8) | *b as u64;
+	}
+	x
+}
+
+/* Reads the rest of the header of a value whose tag was just read. */
+pub fn read<S: Source>(source: &mut S, tag: u8) -> Result<Header, Error> {
+	Ok(match tag {
+		// fixed numbers (7-bit)
+		x if ((x & 0x80) == 0) => Unsigned(x as u64),
+		// fixed negative values (5-bit)
+		x if ((x & 0xE0) == 0xE0) => Signed(x as i8 as i64),
+		// fixed string (5-bit, up to 31 elements)
+		x if ((x & 0xE0) == 0xA0) => Str((x & 0x1F) as uint),
+		// fixed array (4-bit, up to 15 elements)
+		x if ((x & 0xF0) == 0x90) => Array((x & 0xF) as uint),
+		// fixed map (4-bit, up to 15 elements)
+		x if ((x & 0xF0) == 0x80) => Map((x & 0xF) as uint),
+
+		encoded_type::True => Boolean(true),
+		encoded_type::False => Boolean(false),
+		encoded_type::Nil => Nil,
+		encoded_type::Reserved => Reserved,
+		encoded_type::Array16 => Array(try!(source.read_be(2)) as uint),
+		encoded_type::Array32 => Array(try!(source.read_be(4)) as uint),
+		encoded_type::Bin8 => Bin(try!(source.read_be(1)) as uint),
+		encoded_type::Bin16 => Bin(try!(source.read_be(2)) as uint),
+		encoded_type::Bin32 => Bin(try!(source.read_be(4)) as uint),
+		encoded_type::Ext8 => Ext(try!(source.read_be(1)) as uint),
+		encoded_type::Ext16 => Ext(try!(source.read_be(2)) as uint),
+		encoded_type::Ext32 => Ext(try!(source.read_be(4)) as uint),
+		encoded_type::Fixext1 => Ext(1),
+		encoded_type::Fixext2 => Ext(2),
+		encoded_type::Fixext4 => Ext(4),
+		encoded_type::Fixext8 => Ext(8),
+		encoded_type::Fixext16 => Ext(16),
+		encoded_type::Float32 => {
+			let bits = try!(source.read_be(4)) as u32;
+			Float32(unsafe { cast::transmute::<u32, f32>(bits) })
+		}
+		encoded_type::Float64 => {
+			let bits = try!(source.read_be(8));
+			Float64(unsafe { cast::transmute::<u64, f64>(bits) })
+		}
+		encoded_type::Int8 => Signed(try!(source.read_be(1)) as i8 as i64),
+		encoded_type::Int16 => Signed(try!(source.read_be(2)) as i16 as i64),
+		encoded_type::Int32 => Signed(try!(source.read_be(4)) as i32 as i64),
+		encoded_type::Int64 => Signed(try!(source.read_be(8)) as i64),
+		encoded_type::Map16 => Map(try!(source.read_be(2)) as uint),
+		encoded_type::Map32 => Map(try!(source.read_be(4)) as uint),
+		encoded_type::Str8 => Str(try!(source.read_be(1)) as uint),
+		encoded_type::Str16 => Str(try!(source.read_be(2)) as uint),
+		encoded_type::Str32 => Str(try!(source.read_be(4)) as uint),
+		encoded_type::Uint8 => Unsigned(try!(source.read_be(1))),
+		encoded_type::Uint16 => Unsigned(try!(source.read_be(2))),
+		encoded_type::Uint32 => Unsigned(try!(source.read_be(4))),
+		encoded_type::Uint64 => Unsigned(try!(source.read_be(8))),
+
+		/* every byte value is covered above */
+		_ => unreachable!()
+	})
+}
@@ -21,13 +21,31 @@
 	distribution.
  */
 
-#[feature(globs)];
+#[feature(globs, macro_rules)];
 
 #[crate_type="rlib"];
 #[crate_id="msgpack"];
 
+extern mod extra;
+
+/* Unwraps a `Result`, returning early with the error if there was one. */
+macro_rules! try(
+	($e:expr) => (match $e { Ok(x) => x, Err(e) => return Err(e) })
+)
+
+mod header;
 mod magic;
+mod macros;
+mod profile;
 mod testing;
+mod utf8;
 
+pub mod error;
 pub mod encoder;
 pub mod decoder;
+pub mod zerocopy;
+pub mod lossless;
+pub mod canonical;
+pub mod timestamp;
+pub mod extension;
+pub mod unpacker;
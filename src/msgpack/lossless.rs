@@ -0,0 +1,61 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use decoder;
+
+/// A value along with the tag which introduced it on the wire, as read by
+/// `Decoder::read_lossless`. Writing it through `Encoder` uses the same tag
+/// again, so an unmodified tree reproduces its input byte for byte, even
+/// where the input did not use the smallest encoding. Where the tag itself
+/// holds a length or a value, as fixints and fixstrs do, that part is taken
+/// from `data`. A string which is not valid UTF-8 is kept as a `Binary` of
+/// its bytes under the string's own tag, whatever the decoder's UTF-8
+/// policy, so that it too is written back as it was.
+#[deriving(Eq, Clone)]
+pub struct Value {
+	tag: u8,
+	data: Data
+}
+
+#[deriving(Eq, Clone)]
+pub enum Data {
+	/// Anything besides an array or a map. This never holds a
+	/// `decoder::Array` or `decoder::Map`.
+	Scalar(decoder::Value),
+	Array(~[Value]),
+	/// Pairs are kept in wire order, duplicate keys included.
+	Map(~[(Value, Value)])
+}
+
+impl Value {
+	/// Discards the encoding details, leaving an ordinary decoded value.
+	/// Extensions stay as `decoder::Extension`, timestamps included.
+	pub fn to_value(&self) -> decoder::Value {
+		match self.data {
+			Scalar(ref x) => x.clone(),
+			Array(ref items) => decoder::Array(items.iter().map(|x| x.to_value()).collect()),
+			Map(ref pairs) => decoder::Map(pairs.iter()
+				.map(|&(ref k, ref v)| (k.to_value(), v.to_value())).collect())
+		}
+	}
+}
@@ -0,0 +1,279 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+/*
+    Macros which write `Encodable` and `Decodable` implementations for
+    plain structures and enums, so each message type does not need them
+    written out by hand.
+
+    The generated code refers to `Encodable`, `Encoder`, `Decodable`,
+    `Decoder`, `Error`, `TypeMismatch` and `LengthMismatch` by name, so
+    these must be in scope where the macros are used:
+
+        use msgpack::encoder::{Encodable, Encoder};
+        use msgpack::decoder::{Decodable, Decoder};
+        use msgpack::error::{Error, LengthMismatch, TypeMismatch};
+ */
+
+#[macro_escape];
+
+/// Implements `Encodable` and `Decodable` for a structure.
+///
+/// `msgpack_struct!(as_map Point { x: "x", y: "y" default 0 })` writes the
+/// listed fields as a map, under the given keys, which canonical mode sorts
+/// like those of any other map. When reading, keys may arrive in any order
+/// and unknown keys are skipped. A missing key leaves its field at the
+/// given default, or fails with `TypeMismatch` if the field has none.
+///
+/// `msgpack_struct!(as_array Point { x, y default -1 })` writes the listed
+/// fields as an array, in the given order. When reading, extra elements are
+/// skipped, and a shorter array leaves the trailing fields at their
+/// defaults, or fails with `LengthMismatch` if one of them has none.
+///
+/// Fields which are not sent over the wire go in a `skip` list after the
+/// others, along with the value they take when read, as in
+/// `msgpack_struct!(as_map Pixel { x: "x" } skip { dirty: false })`.
+/// Every field of the structure must be listed once.
+#[macro_export]
+macro_rules! msgpack_struct(
+	(as_map $name:ident { $($field:ident : $key:expr $(default $default:expr)*),+ }) => (
+		msgpack_struct!(as_map $name { $($field : $key $(default $default)*),+ } skip { })
+	);
+
+	(as_map $name:ident { $($field:ident : $key:expr $(default $default:expr)*),+ }
+		skip { $($skipped:ident : $value:expr),* }) => (
+		impl Encodable for $name {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				let len = 0u $(+ { let _ = $key; 1u })+;
+				match destination.begin_map(len) {
+					Ok(()) => {},
+					Err(e) => return Err(e)
+				}
+				$(
+					match destination.write_entry(&$key, &self.$field) {
+						Ok(()) => {},
+						Err(e) => return Err(e)
+					}
+				)+
+				destination.end_map()
+			}
+		}
+
+		impl Decodable for $name {
+			fn decode(source: &mut Decoder) -> Result<$name, Error> {
+				$(let mut $field = None;)+
+				let len = match source.read_map_len() {
+					Ok(x) => x,
+					Err(e) => return Err(e)
+				};
+				for _ in range(0, len) {
+					let key = match source.read_as::<~str>() {
+						Ok(x) => x,
+						Err(e) => return Err(e)
+					};
+					$(
+						if key.as_slice() == $key {
+							$field = match source.read_as() {
+								Ok(x) => Some(x),
+								Err(e) => return Err(e)
+							};
+							continue;
+						}
+					)+
+					match source.skip() {
+						Ok(()) => {},
+						Err(e) => return Err(e)
+					}
+				}
+				Ok($name {
+					$($skipped: $value,)*
+					$($field: match $field {
+						Some(x) => x,
+						None => msgpack_missing_field!(source, TypeMismatch $(, $default)*)
+					}),+
+				})
+			}
+		}
+	);
+
+	(as_array $name:ident { $($field:ident $(default $default:expr)*),+ }) => (
+		msgpack_struct!(as_array $name { $($field $(default $default)*),+ } skip { })
+	);
+
+	(as_array $name:ident { $($field:ident $(default $default:expr)*),+ }
+		skip { $($skipped:ident : $value:expr),* }) => (
+		impl Encodable for $name {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				let len = 0u $(+ { let _ = stringify!($field); 1u })+;
+				match destination.write_array_len(len) {
+					Ok(()) => {},
+					Err(e) => return Err(e)
+				}
+				$(
+					match self.$field.encode(destination) {
+						Ok(()) => {},
+						Err(e) => return Err(e)
+					}
+				)+
+				Ok(())
+			}
+		}
+
+		impl Decodable for $name {
+			fn decode(source: &mut Decoder) -> Result<$name, Error> {
+				let len = match source.read_array_len() {
+					Ok(x) => x,
+					Err(e) => return Err(e)
+				};
+				let mut index = 0u;
+				$(
+					let $field = if index < len {
+						index += 1;
+						match source.read_as() {
+							Ok(x) => x,
+							Err(e) => return Err(e)
+						}
+					} else {
+						msgpack_missing_field!(source, LengthMismatch $(, $default)*)
+					};
+				)+
+				while index < len {
+					match source.skip() {
+						Ok(()) => {},
+						Err(e) => return Err(e)
+					}
+					index += 1;
+				}
+				Ok($name {
+					$($skipped: $value,)*
+					$($field: $field),+
+				})
+			}
+		}
+	)
+)
+
+/* The value of a field which `msgpack_struct!` found missing from the
+ * input: its default if it has one, and otherwise a return with the error
+ * of the given kind. */
+#[doc(hidden)]
+#[macro_export]
+macro_rules! msgpack_missing_field(
+	($source:ident, $kind:ident) => (return Err($source.error($kind)));
+	($source:ident, $kind:ident, $default:expr) => ($default)
+)
+
+/// Implements `Encodable` and `Decodable` for an enum, writing each variant
+/// under the name given for it.
+///
+/// `msgpack_enum!(Color { Red: "red", Green: "green" })` covers a C-like
+/// enum, writing each variant as its name.
+///
+/// Variants which carry data are listed in a `with_data` section, naming
+/// each argument: `msgpack_enum!(Shape { Empty: "empty" } with_data {
+/// Circle(r): "circle", Rect(w, h): "rect" })`. These are written as a
+/// single-pair map from the name to an array of the arguments, the same as
+/// the `extra::serialize` implementations write them.
+///
+/// Reading a name which matches no variant, or a variant with the wrong
+/// number of arguments, fails with `TypeMismatch`.
+#[macro_export]
+macro_rules! msgpack_enum(
+	($name:ident { $($variant:ident : $key:expr),+ }) => (
+		impl Encodable for $name {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				match *self {
+					$($variant => destination.write_str($key)),+
+				}
+			}
+		}
+
+		impl Decodable for $name {
+			fn decode(source: &mut Decoder) -> Result<$name, Error> {
+				let key = match source.read_as::<~str>() {
+					Ok(x) => x,
+					Err(e) => return Err(e)
+				};
+				$(
+					if key.as_slice() == $key {
+						return Ok($variant);
+					}
+				)+
+				Err(source.error(TypeMismatch))
+			}
+		}
+	);
+
+	($name:ident { $($variant:ident : $key:expr),* }
+		with_data { $($data_variant:ident ( $($arg:ident),+ ) : $data_key:expr),+ }) => (
+		impl Encodable for $name {
+			fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+				match *self {
+					$($variant => destination.write_variant($key, 0),)*
+					$(
+						$data_variant($(ref $arg),+) => {
+							let len = 0u $(+ { let _ = stringify!($arg); 1u })+;
+							match destination.write_variant($data_key, len) {
+								Ok(()) => {},
+								Err(e) => return Err(e)
+							}
+							$(
+								match $arg.encode(destination) {
+									Ok(()) => {},
+									Err(e) => return Err(e)
+								}
+							)+
+							Ok(())
+						}
+					),+
+				}
+			}
+		}
+
+		impl Decodable for $name {
+			fn decode(source: &mut Decoder) -> Result<$name, Error> {
+				let (key, len) = match source.read_variant() {
+					Ok(x) => x,
+					Err(e) => return Err(e)
+				};
+				$(
+					if key.as_slice() == $key && len == 0 {
+						return Ok($variant);
+					}
+				)*
+				$(
+					let arity = 0u $(+ { let _ = stringify!($arg); 1u })+;
+					if key.as_slice() == $data_key && len == arity {
+						return Ok($data_variant($(
+							match source.read_as() {
+								Ok($arg) => $arg,
+								Err(e) => return Err(e)
+							}
+						),+));
+					}
+				)+
+				Err(source.error(TypeMismatch))
+			}
+		}
+	)
+)
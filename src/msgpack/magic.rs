@@ -53,6 +53,7 @@ pub mod encoded_type {
 	pub static Map16    : u8 = 0xDE;
 	pub static Map32    : u8 = 0xDF;
 	pub static Nil      : u8 = 0xC0;
+	pub static Reserved : u8 = 0xC1;
 	pub static Str16    : u8 = 0xDA;
 	pub static Str32    : u8 = 0xDB;
 	pub static Str8     : u8 = 0xD9;
@@ -0,0 +1,80 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use magic::*;
+
+/* Where a stream is within its open containers: the number of values each
+ * still holds, counting keys and values of a map separately. */
+struct Frame {
+	remaining: uint,
+	map: bool
+}
+
+/// Enforces the JSON profile on a stream of values: map keys must be
+/// strings, and binaries and extensions are not allowed at all.
+pub struct JsonProfile {
+	priv frames: ~[Frame]
+}
+
+impl JsonProfile {
+	pub fn new() -> JsonProfile {
+		JsonProfile { frames: ~[] }
+	}
+
+	/// Takes note of a value starting with `tag`, returning false if it is
+	/// not allowed where it is.
+	pub fn check(&mut self, tag: u8) -> bool {
+		let top = self.frames.len();
+		let key = top > 0 && self.frames[top - 1].map
+			&& self.frames[top - 1].remaining % 2 == 0;
+		if top > 0 {
+			self.frames[top - 1].remaining -= 1;
+			if self.frames[top - 1].remaining == 0 {
+				self.frames.pop();
+			}
+		}
+		match tag {
+			encoded_type::Bin8 | encoded_type::Bin16 | encoded_type::Bin32 => false,
+			encoded_type::Fixext1 | encoded_type::Fixext2 | encoded_type::Fixext4 |
+				encoded_type::Fixext8 | encoded_type::Fixext16 => false,
+			encoded_type::Ext8 | encoded_type::Ext16 | encoded_type::Ext32 => false,
+			x if key => ((x & 0xE0) == 0xA0) || x == encoded_type::Str8
+				|| x == encoded_type::Str16 || x == encoded_type::Str32,
+			_ => true
+		}
+	}
+
+	/// Forgets any open containers, for starting afresh after an error
+	/// left the stream part way through a value.
+	pub fn reset(&mut self) {
+		self.frames = ~[];
+	}
+
+	/// Takes note of the length of the container whose tag was just
+	/// checked; `len` counts pairs for a map.
+	pub fn open(&mut self, len: uint, map: bool) {
+		if len > 0 {
+			self.frames.push(Frame { remaining: if map { len * 2 } else { len }, map: map });
+		}
+	}
+}
@@ -0,0 +1,144 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use decoder::{Decodable, Decoder};
+use encoder::{Encodable, Encoder};
+use error::*;
+
+use std::vec;
+
+use extra::time::Timespec;
+
+/// The extension type code MsgPack reserves for timestamps.
+pub static TypeCode : i8 = -1;
+
+static NanosPerSecond : u32 = 1000000000;
+
+/// A point in time, as seconds and nanoseconds since 1970-01-01 00:00:00
+/// UTC, which is written as MsgPack's timestamp extension. Times before the
+/// epoch have negative `seconds`; `nanoseconds` is always added on, and must
+/// be less than one second's worth.
+#[deriving(Eq, Clone)]
+pub struct Timestamp {
+	seconds: i64,
+	nanoseconds: u32
+}
+
+fn to_be(x: u64, width: uint) -> ~[u8] {
+	vec::from_fn(width, |i| (x >> (8 * (width - 1 - i))) as u8)
+}
+
+fn from_be(data: &[u8]) -> u64 {
+	let mut x : u64 = 0;
+	for b in data.iter() {
+		x = (x << 8) | *b as u64;
+	}
+	x
+}
+
+impl Timestamp {
+	pub fn new(seconds: i64, nanoseconds: u32) -> Timestamp {
+		Timestamp { seconds: seconds, nanoseconds: nanoseconds }
+	}
+
+	/// Converts from the standard library's time type.
+	pub fn from_timespec(t: Timespec) -> Timestamp {
+		/* a negative nsec is carried in to the seconds */
+		if t.nsec < 0 {
+			Timestamp::new(t.sec - 1, (t.nsec + NanosPerSecond as i32) as u32)
+		} else {
+			Timestamp::new(t.sec, t.nsec as u32)
+		}
+	}
+
+	/// Converts to the standard library's time type.
+	pub fn to_timespec(&self) -> Timespec {
+		Timespec::new(self.seconds, self.nanoseconds as i32)
+	}
+
+	/// Returns the extension payload for this time, in the smallest of the
+	/// 32, 64 and 96-bit layouts which holds it, or `None` if `nanoseconds`
+	/// is out of range.
+	pub fn to_bytes(&self) -> Option<~[u8]> {
+		if self.nanoseconds >= NanosPerSecond {
+			return None;
+		}
+		if self.seconds >= 0 && (self.seconds >> 34) == 0 {
+			/* 30 bits of nanoseconds above 34 bits of seconds; without
+			 * nanoseconds, small enough times fit in 32 bits */
+			let x = (self.nanoseconds as u64 << 34) | self.seconds as u64;
+			if (x >> 32) == 0 {
+				Some(to_be(x, 4))
+			} else {
+				Some(to_be(x, 8))
+			}
+		} else {
+			let mut data = to_be(self.nanoseconds as u64, 4);
+			data.push_all_move(to_be(self.seconds as u64, 8));
+			Some(data)
+		}
+	}
+
+	/// Reads a time back from an extension payload in any of the three
+	/// layouts, or returns `None` if the payload is malformed.
+	pub fn from_bytes(data: &[u8]) -> Option<Timestamp> {
+		let t = match data.len() {
+			4 => Timestamp::new(from_be(data) as i64, 0),
+			8 => {
+				let x = from_be(data);
+				Timestamp::new((x & 0x3FFFFFFFF) as i64, (x >> 34) as u32)
+			}
+			12 => Timestamp::new(from_be(data.slice_from(4)) as i64,
+				from_be(data.slice_to(4)) as u32),
+			_ => return None
+		};
+		if t.nanoseconds < NanosPerSecond {
+			Some(t)
+		} else {
+			None
+		}
+	}
+}
+
+impl Encodable for Timestamp {
+	fn encode(&self, destination: &mut Encoder) -> Result<(), Error> {
+		match self.to_bytes() {
+			Some(data) => destination.write_reserved_ext(TypeCode, data),
+			None => Err(destination.error(InvalidTimestamp))
+		}
+	}
+}
+
+impl Decodable for Timestamp {
+	fn decode(source: &mut Decoder) -> Result<Timestamp, Error> {
+		let (type_code, len) = try!(source.read_ext_header());
+		if type_code != TypeCode {
+			return Err(source.error(TypeMismatch));
+		}
+		let data = try!(source.read_raw(len));
+		match Timestamp::from_bytes(data) {
+			Some(t) => Ok(t),
+			None => Err(source.error(InvalidTimestamp))
+		}
+	}
+}
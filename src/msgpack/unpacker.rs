@@ -0,0 +1,237 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use decoder::{Decoder, DecoderLimits, Value};
+use error::*;
+use header;
+
+use std::io;
+use std::io::mem;
+use std::num::{CheckedAdd, CheckedMul};
+use std::uint;
+
+/// Collects bytes as they arrive, for example from a non-blocking socket,
+/// and hands back each value once all of its bytes are present. Incomplete
+/// trailing data stays buffered until the next `feed`.
+///
+/// Once a value is found to be incomplete, the headers which did arrive
+/// show how many more bytes it needs at least, and it is not decoded again
+/// until those are in. A large string or binary arriving in small pieces is
+/// therefore decoded once, rather than once per piece.
+pub struct Unpacker {
+	priv limits: DecoderLimits,
+	priv buffer: ~[u8],
+	/* bytes at the front of `buffer` which were already returned */
+	priv consumed: uint,
+	/* bytes dropped from the front of `buffer` so far, for error offsets */
+	priv discarded: u64,
+	/* set once an error other than running out of data was returned */
+	priv poisoned: bool,
+	/* buffered bytes the oldest incomplete value needs before it is worth
+	 * decoding again */
+	priv needed: uint
+}
+
+/* Walks over the headers of a buffered value without decoding it. */
+struct Scan<'a> {
+	data: &'a [u8],
+	position: uint
+}
+
+impl<'a> header::Source for Scan<'a> {
+	fn read_be(&mut self, width: uint) -> Result<u64, Error> {
+		if width > self.data.len() - self.position {
+			return Err(Error::new(UnexpectedEof, self.data.len() as u64, None));
+		}
+		let x = header::from_be(self.data.slice(self.position, self.position + width));
+		self.position += width;
+		Ok(x)
+	}
+}
+
+/* A lower bound on the length of the value at the start of `data`, which
+ * was found to be incomplete: what its headers show so far, plus a byte for
+ * each element not reached yet. Anything `limits` refuse gives 0, so that
+ * the decoder is run again at once to report it. */
+fn needed(data: &[u8], limits: &DecoderLimits) -> uint {
+	let mut scan = Scan { data: data, position: 0 };
+	/* values still to come in each open container, innermost last, under
+	 * the one for the value itself */
+	let mut open : ~[uint] = ~[1];
+	while scan.position < data.len() {
+		let tag = data[scan.position];
+		scan.position += 1;
+		let top = open.len() - 1;
+		open[top] -= 1;
+		let (body, fits) = match header::read(&mut scan, tag) {
+			Err(_) => return data.len() + 1,
+			Ok(header::Str(len)) => (len, len <= limits.max_str_len),
+			Ok(header::Bin(len)) => (len, len <= limits.max_bin_len),
+			Ok(header::Ext(len)) => {
+				/* the type code */
+				scan.position += 1;
+				(len, len <= limits.max_ext_len)
+			}
+			Ok(header::Array(len)) => {
+				open.push(len);
+				(0, len <= limits.max_container_len)
+			}
+			Ok(header::Map(len)) => match len.checked_mul(&2) {
+				Some(x) => {
+					open.push(x);
+					(0, len <= limits.max_container_len)
+				}
+				None => return 0
+			},
+			Ok(header::Reserved) => return 0,
+			Ok(_) => (0, true)
+		};
+		if !fits || open.len() - 1 > limits.max_depth {
+			return 0;
+		}
+		scan.position = match scan.position.checked_add(&body) {
+			Some(x) => x,
+			None => return 0
+		};
+		while open.len() > 0 && open[open.len() - 1] == 0 {
+			open.pop();
+		}
+		if open.len() == 0 {
+			/* complete after all, so whatever stopped the decoder will
+			 * stop it again */
+			return 0;
+		}
+	}
+	let mut total = scan.position;
+	for n in open.iter() {
+		total = match total.checked_add(n) {
+			Some(x) => x,
+			None => uint::max_value
+		};
+	}
+	if (total as u64) > limits.max_total_bytes {
+		limits.max_total_bytes as uint
+	} else {
+		total
+	}
+}
+
+impl Unpacker {
+	pub fn new() -> Unpacker {
+		Unpacker::with_limits(DecoderLimits::unlimited())
+	}
+
+	/// Creates an unpacker which refuses values exceeding `limits`. Here
+	/// `max_total_bytes` applies to each value separately, and also bounds
+	/// how much an incomplete value may buffer.
+	pub fn with_limits(limits: DecoderLimits) -> Unpacker {
+		Unpacker {
+			limits: limits,
+			buffer: ~[],
+			consumed: 0,
+			discarded: 0,
+			poisoned: false,
+			needed: 0
+		}
+	}
+
+	/// Appends bytes to the end of the buffered input.
+	pub fn feed(&mut self, data: &[u8]) {
+		self.compact();
+		self.buffer.push_all(data);
+	}
+
+	/// Returns the number of bytes fed in which have not yet been returned
+	/// as part of a value.
+	pub fn buffered(&self) -> uint {
+		self.buffer.len() - self.consumed
+	}
+
+	/// Drops all buffered input. After an error other than running out of
+	/// data the buffered bytes cannot be decoded, and this must be called
+	/// before the unpacker is usable again.
+	pub fn reset(&mut self) {
+		self.discarded += self.buffer.len() as u64;
+		self.buffer = ~[];
+		self.consumed = 0;
+		self.poisoned = false;
+		self.needed = 0;
+	}
+
+	/// Returns true if decoding stopped with an error, and `reset` has not
+	/// been called since.
+	pub fn is_poisoned(&self) -> bool {
+		self.poisoned
+	}
+
+	/// Returns the next complete value, `None` if more data has to be fed
+	/// in first, or the error which stopped decoding. Error offsets count
+	/// from the first byte ever fed in. An error is returned only once;
+	/// after it, every poll returns `None` until `reset` is called.
+	pub fn poll(&mut self) -> Option<Result<Value, Error>> {
+		if self.poisoned || self.buffered() == 0 || self.buffered() < self.needed {
+			return None;
+		}
+		let (result, used) = {
+			let mut reader = mem::BufReader::new(self.buffer.slice_from(self.consumed));
+			let mut unpacker = Decoder::with_limits(&mut reader as &mut io::Reader,
+				self.limits);
+			let result = unpacker.try_read();
+			(result, unpacker.position())
+		};
+		match result {
+			Ok(value) => {
+				self.consumed += used as uint;
+				self.needed = 0;
+				Some(Ok(value))
+			}
+			Err(Error { kind: UnexpectedEof, .. }) => {
+				self.needed = needed(self.buffer.slice_from(self.consumed), &self.limits);
+				None
+			}
+			Err(e) => {
+				self.poisoned = true;
+				let base = self.discarded + self.consumed as u64;
+				Some(Err(Error::new(e.kind, e.offset + base, e.tag)))
+			}
+		}
+	}
+
+	/* Drops bytes which were already returned, once they make up most of
+	 * the buffer. */
+	fn compact(&mut self) {
+		if self.consumed > 0 && self.consumed >= self.buffer.len() / 2 {
+			self.buffer = self.buffer.slice_from(self.consumed).to_owned();
+			self.discarded += self.consumed as u64;
+			self.consumed = 0;
+		}
+	}
+}
+
+impl Iterator<Result<Value, Error>> for Unpacker {
+	/// Equivalent to `poll`; iteration ends whenever more data is needed,
+	/// and may be resumed after the next `feed`.
+	fn next(&mut self) -> Option<Result<Value, Error>> {
+		self.poll()
+	}
+}
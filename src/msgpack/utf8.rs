@@ -0,0 +1,81 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+/* UTF-8 checking for both decoders, which must agree on what is valid. */
+
+use std::str;
+
+/* Returns the length of the valid UTF-8 sequence at the start of `data`, or
+ * the length of the invalid part there (at least one byte). The narrower
+ * ranges for some second bytes rule out overlong forms, surrogates and code
+ * points above U+10FFFF. */
+fn sequence(data: &[u8]) -> Result<uint, uint> {
+	let (len, low, high) = match data[0] {
+		0x00 .. 0x7F => return Ok(1),
+		0xC2 .. 0xDF => (2, 0x80, 0xBF),
+		0xE0 => (3, 0xA0, 0xBF),
+		0xE1 .. 0xEC | 0xEE .. 0xEF => (3, 0x80, 0xBF),
+		0xED => (3, 0x80, 0x9F),
+		0xF0 => (4, 0x90, 0xBF),
+		0xF1 .. 0xF3 => (4, 0x80, 0xBF),
+		0xF4 => (4, 0x80, 0x8F),
+		_ => return Err(1)
+	};
+	for i in range(1, len) {
+		let (min, max) = if i == 1 { (low, high) } else { (0x80u8, 0xBFu8) };
+		if i >= data.len() || data[i] < min || data[i] > max {
+			return Err(i);
+		}
+	}
+	Ok(len)
+}
+
+/* Index of the first byte which is not part of valid UTF-8, if any. */
+pub fn error_at(data: &[u8]) -> Option<uint> {
+	let mut i = 0;
+	while i < data.len() {
+		match sequence(data.slice_from(i)) {
+			Ok(n) => i += n,
+			Err(_) => return Some(i)
+		}
+	}
+	None
+}
+
+pub fn from_lossy(data: &[u8]) -> ~str {
+	let mut s = str::with_capacity(data.len());
+	let mut i = 0;
+	while i < data.len() {
+		match sequence(data.slice_from(i)) {
+			Ok(n) => {
+				s.push_str(str::from_utf8(data.slice(i, i + n)));
+				i += n;
+			}
+			Err(n) => {
+				s.push_char('\uFFFD');
+				i += n;
+			}
+		}
+	}
+	s
+}
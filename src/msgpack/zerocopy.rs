@@ -0,0 +1,262 @@
+/*
+	Copyright (c) 2014 Joshua A. Cearley
+
+	This software is provided as-is, without any express or implied
+	warranty. In no event will the authors be held liable for any damages
+	arising from the use of this software.
+
+	Permission is granted to anyone to use this software for any purpose,
+	including commercial applications, and to alter it and redistribute
+	it freely, subject to the following restrictions:
+
+	1. The origin of this software must not be misrepresented; you must not
+	claim that you wrote the original software. If you use this software
+	in a product, an acknowledgment in the product documentation would be
+	appreciated but is not required.
+
+	2. Altered source versions must be plainly marked as such, and must not
+	be misrepresented as being the original software.
+
+	3. This notice may not be removed or altered from any source
+	distribution.
+ */
+
+use decoder::DecoderLimits;
+use error::*;
+use header;
+use timestamp;
+use timestamp::Timestamp;
+use utf8;
+
+use std::num::CheckedAdd;
+use std::str;
+use std::vec;
+
+/// A decoded value which borrows its strings, binaries and extension
+/// payloads from the buffer it was read from. Arrays and maps still
+/// allocate a vector to hold their elements.
+pub enum Value<'a> {
+	Signed(i64),
+	Unsigned(u64),
+	String(&'a str),
+	Binary(&'a [u8]),
+	Array(~[Value<'a>]),
+	Float32(f32),
+	Float64(f64),
+	Extension(i8, &'a [u8]),
+	/// A timestamp extension (type -1), which is decoded here just as the
+	/// streaming `Decoder` does.
+	Time(Timestamp),
+	/* pairs are kept in wire order, duplicate keys included */
+	Map(~[(Value<'a>, Value<'a>)]),
+	Boolean(bool),
+	Nil
+}
+
+/// Reads MsgPack values from a byte buffer which is already in memory,
+/// without copying the variable-length parts out of it.
+pub struct Decoder<'a> {
+	priv data: &'a [u8],
+	priv limits: DecoderLimits,
+	/* containers currently open */
+	priv depth: uint,
+	/* index of the next unread byte */
+	priv position: uint,
+	/* index and tag of the value currently being read, for error
+	 * reporting */
+	priv start: uint,
+	priv tag: Option<u8>
+}
+
+impl<'a> Decoder<'a> {
+	/// Creates a decoder without any limits, beyond the size of the buffer;
+	/// see `with_limits` for reading untrusted input.
+	pub fn new(data: &'a [u8]) -> Decoder<'a> {
+		Decoder::with_limits(data, DecoderLimits::unlimited())
+	}
+
+	/// Creates a decoder which refuses input exceeding `limits`.
+	pub fn with_limits(data: &'a [u8], limits: DecoderLimits) -> Decoder<'a> {
+		Decoder {
+			data: data,
+			limits: limits,
+			depth: 0,
+			position: 0,
+			start: 0,
+			tag: None
+		}
+	}
+
+	/// Returns the number of bytes consumed so far.
+	pub fn position(&self) -> uint {
+		self.position
+	}
+
+	/// Returns the part of the buffer which has not been read yet.
+	pub fn remaining(&self) -> &'a [u8] {
+		self.data.slice_from(self.position)
+	}
+}
+
+/* Byte-level reading straight out of the buffer. Errors are reported at
+ * the start of the value being read, as the stream decoder reports them,
+ * except that running out of data is reported at the end of the buffer. */
+impl<'a> Decoder<'a> {
+	fn error(&self, kind: ErrorKind) -> Error {
+		Error::new(kind, self.start as u64, self.tag)
+	}
+
+	fn eof(&self) -> Error {
+		Error::new(UnexpectedEof, self.data.len() as u64, self.tag)
+	}
+
+	fn take(&mut self, len: uint) -> Result<&'a [u8], Error> {
+		let end = match self.position.checked_add(&len) {
+			Some(x) => x,
+			None => return Err(self.eof())
+		};
+		if (end as u64) > self.limits.max_total_bytes {
+			return Err(self.error(SizeLimitExceeded));
+		}
+		if end > self.data.len() {
+			return Err(self.eof());
+		}
+		let data : &'a [u8] = self.data;
+		let start = self.position;
+		self.position += len;
+		Ok(data.slice(start, start + len))
+	}
+
+	fn read_byte(&mut self) -> Result<u8, Error> {
+		Ok(try!(self.take(1))[0])
+	}
+}
+
+impl<'a> header::Source for Decoder<'a> {
+	fn read_be(&mut self, width: uint) -> Result<u64, Error> {
+		Ok(header::from_be(try!(self.take(width))))
+	}
+}
+
+impl<'a> Decoder<'a> {
+	pub fn read(&mut self) -> Value<'a> {
+		let x = self.try_read();
+		match x {
+			Ok(y) => y,
+			Err(e) => fail!(e.to_str())
+		}
+	}
+
+	/// Reads the next value from the buffer. Unlike the stream decoder, a
+	/// failed read leaves the position where it was before the call, so the
+	/// read may be retried once more data is available.
+	pub fn try_read(&mut self) -> Result<Value<'a>, Error> {
+		let start = self.position;
+		let result = self.read_value();
+		if result.is_err() {
+			self.position = start;
+			self.depth = 0;
+		}
+		result
+	}
+
+	fn check_len(&self, len: uint, max: uint) -> Result<(), Error> {
+		if len > max {
+			Err(self.error(SizeLimitExceeded))
+		} else {
+			Ok(())
+		}
+	}
+
+	/* Every element takes at least one byte, so a container which needs
+	 * more bytes than are left can be refused before anything is allocated
+	 * for it. */
+	fn enter(&mut self, len: uint, min_bytes: uint) -> Result<(), Error> {
+		try!(self.check_len(len, self.limits.max_container_len));
+		if self.depth >= self.limits.max_depth {
+			return Err(self.error(DepthLimitExceeded));
+		}
+		if min_bytes > self.data.len() - self.position {
+			return Err(self.eof());
+		}
+		self.depth += 1;
+		Ok(())
+	}
+
+	#[allow(experimental)]
+	fn read_array(&mut self, elements: uint) -> Result<Value<'a>, Error> {
+		try!(self.enter(elements, elements));
+		let mut accum : ~[Value<'a>] = vec::with_capacity(elements);
+		let mut current = 0;
+		while (current < elements) {
+			accum.push(try!(self.read_value()));
+			current += 1;
+		}
+		self.depth -= 1;
+		Ok(Array(accum))
+	}
+
+	#[allow(experimental)]
+	fn read_map(&mut self, pairs: uint) -> Result<Value<'a>, Error> {
+		try!(self.enter(pairs, pairs * 2));
+		let mut accum : ~[(Value<'a>, Value<'a>)] = vec::with_capacity(pairs);
+		let mut current = 0;
+		while (current < pairs) {
+			let key = try!(self.read_value());
+			let value = try!(self.read_value());
+			accum.push((key, value));
+			current += 1;
+		}
+		self.depth -= 1;
+		Ok(Map(accum))
+	}
+
+	fn read_str(&mut self, len: uint) -> Result<Value<'a>, Error> {
+		try!(self.check_len(len, self.limits.max_str_len));
+		let start = self.position;
+		let data = try!(self.take(len));
+		match utf8::error_at(data) {
+			Some(bad) => Err(Error::new(InvalidUtf8, (start + bad) as u64, self.tag)),
+			None => Ok(String(str::from_utf8(data)))
+		}
+	}
+
+	fn read_bin(&mut self, len: uint) -> Result<Value<'a>, Error> {
+		try!(self.check_len(len, self.limits.max_bin_len));
+		Ok(Binary(try!(self.take(len))))
+	}
+
+	fn read_ext(&mut self, len: uint) -> Result<Value<'a>, Error> {
+		try!(self.check_len(len, self.limits.max_ext_len));
+		let type_code = try!(self.read_byte()) as i8;
+		let data = try!(self.take(len));
+		if type_code != timestamp::TypeCode {
+			return Ok(Extension(type_code, data));
+		}
+		match Timestamp::from_bytes(data) {
+			Some(t) => Ok(Time(t)),
+			None => Err(self.error(InvalidTimestamp))
+		}
+	}
+
+	fn read_value(&mut self) -> Result<Value<'a>, Error> {
+		self.start = self.position;
+		self.tag = None;
+		let tag = try!(self.read_byte());
+		self.tag = Some(tag);
+		match try!(header::read(self, tag)) {
+			header::Unsigned(x) => Ok(Unsigned(x)),
+			header::Signed(x) => Ok(Signed(x)),
+			header::Float32(x) => Ok(Float32(x)),
+			header::Float64(x) => Ok(Float64(x)),
+			header::Boolean(x) => Ok(Boolean(x)),
+			header::Nil => Ok(Nil),
+			header::Str(len) => self.read_str(len),
+			header::Bin(len) => self.read_bin(len),
+			header::Ext(len) => self.read_ext(len),
+			header::Array(len) => self.read_array(len),
+			header::Map(len) => self.read_map(len),
+			header::Reserved => Err(self.error(ReservedTag))
+		}
+	}
+}